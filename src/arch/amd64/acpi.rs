@@ -0,0 +1,241 @@
+//! Just enough ACPI to find the tables we need and parse the MADT.
+//!
+//! Firmware may put the tables anywhere, RESERVED memory included, which the mapping of all
+//! physical memory leaves out. Every table is therefore mapped on its own with `mmio::ioremap`
+//! for as long as it is looked at.
+//!
+//! Tables in ACPI_RECLAIM memory can only be looked at until `init` hands that memory to the frame
+//! allocator.
+use core::{ptr, slice};
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use log::{debug, info, warn};
+
+use heapless::consts::U8 as HLU8;
+use heapless::consts::U16 as HLU16;
+
+use super::mmio::{self, CacheMode};
+
+/// Physical address of the RSDP, as found in the UEFI configuration table.
+static mut RSDP: Option<PhysAddr> = None;
+
+/// Offsets within the RSDP.
+const RSDP_REVISION: usize = 15;
+const RSDP_RSDT_ADDRESS: usize = 16;
+const RSDP_XSDT_ADDRESS: usize = 24;
+/// Size of an ACPI 2.0 RSDP, which is all of it we look at.
+const RSDP_SIZE: usize = 36;
+
+const SDT_HEADER_SIZE: usize = 36;
+/// Offset of the length within an SDT header.
+const SDT_LENGTH: usize = 4;
+
+/// Offsets within the MADT.
+const MADT_LOCAL_APIC_ADDRESS: usize = 36;
+const MADT_FLAGS: usize = 40;
+const MADT_ENTRIES: usize = 44;
+const MADT_PCAT_COMPAT: u32 = 1;
+
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Remember where the firmware put the RSDP, before exiting boot services.
+pub fn set_rsdp(addr: PhysAddr) {
+    unsafe { RSDP = Some(addr); }
+}
+
+/// Firmware memory mapped for as long as this lives.
+struct Mapping {
+    virt: VirtAddr,
+    length: usize,
+}
+
+impl Mapping {
+    fn new(phys: PhysAddr, length: usize) -> Option<Self> {
+        match unsafe { mmio::ioremap(phys, length as u64, CacheMode::WriteBack) } {
+            Ok(virt) => Some(Self { virt, length }),
+            Err(err) => {
+                warn!("Failed to map ACPI data at {:?}: {:?}", phys, err);
+                None
+            }
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.as_ptr::<u8>(), self.length) }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_unaligned((self.virt + offset as u64).as_ptr::<u32>()) }
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_unaligned((self.virt + offset as u64).as_ptr::<u64>()) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { mmio::iounmap(self.virt); }
+    }
+}
+
+/// A system description table, header included, mapped for as long as this lives.
+pub struct Sdt {
+    pub phys: PhysAddr,
+    pub length: usize,
+    mapping: Mapping,
+}
+
+impl Sdt {
+    /// Map the table at `phys`, header first to find out how long it is.
+    fn map(phys: PhysAddr) -> Option<Self> {
+        let length = Mapping::new(phys, SDT_HEADER_SIZE)?.read_u32(SDT_LENGTH) as usize;
+        if length < SDT_HEADER_SIZE {
+            warn!("Ignoring table at {:?} with a length of only {} bytes.", phys, length);
+            return None;
+        }
+        Some(Self { phys, length, mapping: Mapping::new(phys, length)? })
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        let mut signature = [0u8; 4];
+        signature.copy_from_slice(&self.bytes()[..4]);
+        signature
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.mapping.bytes()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.bytes().iter().fold(0u8, |sum, it| sum.wrapping_add(*it)) == 0
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        self.bytes()[offset]
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        let bytes = &self.bytes()[offset..offset + 2];
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        let bytes = &self.bytes()[offset..offset + 4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        self.read_u32(offset) as u64 | (self.read_u32(offset + 4) as u64) << 32
+    }
+}
+
+/// The table with the given signature, if the firmware provides a valid one.
+pub fn find_table(signature: &[u8; 4]) -> Option<Sdt> {
+    let rsdp = unsafe { RSDP.expect("No RSDP, ACPI is not available.") };
+    // ACPI 2.0 and up have a XSDT with 64-bit pointers, before that there's only the RSDT, and
+    // only the first 20 bytes of the RSDP.
+    let (root, entry_size) = {
+        let rsdp = Mapping::new(rsdp, RSDP_SIZE)?;
+        if rsdp.bytes()[RSDP_REVISION] >= 2 {
+            (Sdt::map(PhysAddr::new(rsdp.read_u64(RSDP_XSDT_ADDRESS)))?, 8)
+        } else {
+            (Sdt::map(PhysAddr::new(rsdp.read_u32(RSDP_RSDT_ADDRESS) as u64))?, 4)
+        }
+    };
+    if !root.is_valid() {
+        warn!("Root system description table at {:?} is invalid.", root.phys);
+        return None;
+    }
+
+    for offset in (SDT_HEADER_SIZE..root.length - entry_size + 1).step_by(entry_size) {
+        let phys = if entry_size == 8 { root.read_u64(offset) } else { root.read_u32(offset) as u64 };
+        let table = match Sdt::map(PhysAddr::new(phys)) {
+            Some(table) => table,
+            None => continue,
+        };
+        if table.signature() != *signature { continue; }
+        if table.is_valid() {
+            return Some(table);
+        }
+        warn!("Ignoring {} table at {:?} with a bad checksum.", core::str::from_utf8(signature).unwrap_or("?"), table.phys);
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First GSI this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not identity mapped to a GSI, or not edge triggered and active high.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1 and trigger mode in bits 2-3.
+    pub flags: u16,
+}
+
+/// What the MADT tells us about the interrupt controllers.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether there is a pair of legacy 8259 PICs to be disabled.
+    pub has_8259: bool,
+    pub io_apics: heapless::Vec<IoApicInfo, HLU8>,
+    pub overrides: heapless::Vec<InterruptSourceOverride, HLU16>,
+}
+
+/// Parse the MADT, panicking if there is none as we can't do without APICs.
+pub fn parse_madt() -> Madt {
+    let table = find_table(b"APIC").expect("No MADT, can't set up interrupt controllers.");
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(table.read_u32(MADT_LOCAL_APIC_ADDRESS) as u64),
+        has_8259: table.read_u32(MADT_FLAGS) & MADT_PCAT_COMPAT != 0,
+        io_apics: heapless::Vec::new(),
+        overrides: heapless::Vec::new(),
+    };
+
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= table.length {
+        let (ty, length) = (table.read_u8(offset), table.read_u8(offset + 1) as usize);
+        if length < 2 || offset + length > table.length {
+            warn!("Malformed MADT entry at offset {}, ignoring the rest.", offset);
+            break;
+        }
+
+        match ty {
+            MADT_IO_APIC => {
+                let io_apic = IoApicInfo {
+                    id: table.read_u8(offset + 2),
+                    address: PhysAddr::new(table.read_u32(offset + 4) as u64),
+                    gsi_base: table.read_u32(offset + 8),
+                };
+                if madt.io_apics.push(io_apic).is_err() { warn!("Too many I/O APICs, ignoring {:?}.", io_apic); }
+            }
+            MADT_INTERRUPT_SOURCE_OVERRIDE => {
+                let entry = InterruptSourceOverride {
+                    irq: table.read_u8(offset + 3),
+                    gsi: table.read_u32(offset + 4),
+                    flags: table.read_u16(offset + 8),
+                };
+                if madt.overrides.push(entry).is_err() { warn!("Too many interrupt source overrides, ignoring {:?}.", entry); }
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => madt.local_apic_address = PhysAddr::new(table.read_u64(offset + 4)),
+            _ => debug!("Skipping MADT entry of type {}.", ty),
+        }
+        offset += length;
+    }
+
+    info!("MADT: local APIC at {:?}, {} I/O APIC(s), {} interrupt source override(s){}.",
+        madt.local_apic_address, madt.io_apics.len(), madt.overrides.len(),
+        if madt.has_8259 { ", legacy PICs present" } else { "" });
+    madt
+}
@@ -0,0 +1,370 @@
+//! Interrupt controllers: the local APIC, in xAPIC or x2APIC mode, and the I/O APICs that route
+//! device interrupts to it. The legacy 8259 PICs are masked and left alone.
+//!
+//! Drivers register a handler for a GSI, or for an ISA IRQ which is translated to a GSI through
+//! the MADT's interrupt source overrides, and get a vector allocated for it. Handlers run with
+//! interrupts disabled, the local APIC is sent an EOI once they return.
+use core::ptr;
+
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::model_specific::Msr;
+use x86_64::instructions::port::Port;
+
+use x86::cpuid::CpuId;
+
+use log::{info, warn};
+
+use heapless::consts::U8 as HLU8;
+use heapless::consts::U16 as HLU16;
+
+use super::acpi::{self, InterruptSourceOverride};
+use super::interrupts::{self, InterruptHandler};
+use super::mmio::{self, CacheMode};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// x2APIC registers are MSRs from here on, one for every 16 bytes of the xAPIC register space.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Local APIC registers, as offsets into the xAPIC register space.
+pub const LAPIC_ID: u32 = 0x20;
+pub const LAPIC_VERSION: u32 = 0x30;
+pub const LAPIC_TPR: u32 = 0x80;
+pub const LAPIC_EOI: u32 = 0xB0;
+pub const LAPIC_SVR: u32 = 0xF0;
+pub const LAPIC_ESR: u32 = 0x280;
+pub const LAPIC_LVT_TIMER: u32 = 0x320;
+pub const LAPIC_LVT_LINT0: u32 = 0x350;
+pub const LAPIC_LVT_LINT1: u32 = 0x360;
+pub const LAPIC_LVT_ERROR: u32 = 0x370;
+pub const LAPIC_TIMER_INITIAL_COUNT: u32 = 0x380;
+pub const LAPIC_TIMER_CURRENT_COUNT: u32 = 0x390;
+pub const LAPIC_TIMER_DIVIDE: u32 = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+pub const LVT_MASKED: u32 = 1 << 16;
+
+/// Interrupts the local APIC delivers when it can't tell what it was asked to deliver. They must
+/// not be sent an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// I/O APIC registers, selected through IOREGSEL and accessed through IOWIN.
+const IOAPIC_IOREGSEL: u64 = 0x00;
+const IOAPIC_IOWIN: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Redirection entry bits.
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+/// The destination field of a redirection entry holds an 8 bit APIC ID, larger ones can only be
+/// reached through interrupt remapping.
+const REDIRECTION_MAX_DESTINATION: u32 = 0xFF;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+/// Where the PICs' vectors are moved, out of the way of exceptions, should they fire anyway.
+const PIC_VECTOR_BASE: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// No I/O APIC handles the GSI.
+    NoSuchGsi,
+    /// Another handler is registered for the GSI already.
+    InUse,
+    /// All vectors are taken.
+    OutOfVectors,
+    /// Our local APIC ID doesn't fit in a redirection entry, see `REDIRECTION_MAX_DESTINATION`.
+    UnreachableApic,
+}
+
+/// How an interrupt is signalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    EdgeActiveHigh,
+    EdgeActiveLow,
+    LevelActiveHigh,
+    LevelActiveLow,
+}
+
+impl Trigger {
+    /// Decode MPS INTI flags as found in interrupt source overrides, where "conforming" means
+    /// whatever the bus defaults to, which for ISA is edge triggered and active high.
+    fn from_inti_flags(flags: u16) -> Self {
+        let active_low = flags & 0b11 == 0b11;
+        let level = (flags >> 2) & 0b11 == 0b11;
+        match (level, active_low) {
+            (false, false) => Trigger::EdgeActiveHigh,
+            (false, true) => Trigger::EdgeActiveLow,
+            (true, false) => Trigger::LevelActiveHigh,
+            (true, true) => Trigger::LevelActiveLow,
+        }
+    }
+
+    fn redirection_bits(&self) -> u64 {
+        match self {
+            Trigger::EdgeActiveHigh => 0,
+            Trigger::EdgeActiveLow => REDIRECTION_ACTIVE_LOW,
+            Trigger::LevelActiveHigh => REDIRECTION_LEVEL_TRIGGERED,
+            Trigger::LevelActiveLow => REDIRECTION_LEVEL_TRIGGERED | REDIRECTION_ACTIVE_LOW,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum LocalApicMode {
+    /// Registers are memory mapped at the given address.
+    XApic(VirtAddr),
+    /// Registers are MSRs.
+    X2Apic,
+}
+
+#[derive(Debug)]
+pub struct LocalApic {
+    mode: LocalApicMode,
+}
+
+impl LocalApic {
+    pub fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            match self.mode {
+                LocalApicMode::XApic(base) => ptr::read_volatile((base + reg as u64).as_ptr::<u32>()),
+                LocalApicMode::X2Apic => Msr::new(X2APIC_MSR_BASE + (reg >> 4)).read() as u32,
+            }
+        }
+    }
+
+    pub fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            match self.mode {
+                LocalApicMode::XApic(base) => ptr::write_volatile((base + reg as u64).as_mut_ptr::<u32>(), value),
+                LocalApicMode::X2Apic => Msr::new(X2APIC_MSR_BASE + (reg >> 4)).write(value as u64),
+            }
+        }
+    }
+
+    /// APIC ID of the CPU we're running on, as I/O APICs address it.
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            LocalApicMode::XApic(_) => self.read(LAPIC_ID) >> 24,
+            LocalApicMode::X2Apic => self.read(LAPIC_ID),
+        }
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+}
+
+#[derive(Debug)]
+struct IoApic {
+    info: acpi::IoApicInfo,
+    registers: VirtAddr,
+    /// Amount of GSIs it handles.
+    inputs: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.registers + IOAPIC_IOREGSEL).as_mut_ptr::<u32>(), reg);
+            ptr::read_volatile((self.registers + IOAPIC_IOWIN).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.registers + IOAPIC_IOREGSEL).as_mut_ptr::<u32>(), reg);
+            ptr::write_volatile((self.registers + IOAPIC_IOWIN).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.info.gsi_base && gsi < self.info.gsi_base + self.inputs
+    }
+
+    fn redirection(&self, gsi: u32) -> u64 {
+        let reg = IOAPIC_REDIRECTION_TABLE + (gsi - self.info.gsi_base) * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION_TABLE + (gsi - self.info.gsi_base) * 2;
+        // Mask the entry while it is half written.
+        self.write(reg, (entry as u32) | REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/// A GSI a handler is registered for.
+#[derive(Debug, Clone, Copy)]
+struct Irq {
+    gsi: u32,
+    vector: u8,
+}
+
+struct Apics {
+    local: LocalApic,
+    io: heapless::Vec<IoApic, HLU8>,
+    overrides: heapless::Vec<InterruptSourceOverride, HLU16>,
+    irqs: heapless::Vec<Irq, HLU16>,
+}
+
+static mut APICS: Option<Apics> = None;
+
+/// Mask the legacy PICs, enable the local APIC and set up the I/O APICs with all their inputs
+/// masked.
+///
+/// To be called with interrupts disabled, once the IDT and `mmio` are set up.
+pub unsafe fn init() {
+    let madt = acpi::parse_madt();
+    if madt.has_8259 {
+        disable_pic();
+    }
+
+    let local = enable_local_apic(madt.local_apic_address);
+    let mut io = heapless::Vec::new();
+    for info in madt.io_apics.iter() {
+        let registers = mmio::ioremap(info.address, 0x20, CacheMode::Uncacheable).expect("Failed to map an I/O APIC.");
+        let mut io_apic = IoApic { info: *info, registers, inputs: 0 };
+        io_apic.inputs = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        for gsi in info.gsi_base..info.gsi_base + io_apic.inputs {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+        info!("I/O APIC {} at {:?} handles GSI {} to {}.", info.id, info.address, info.gsi_base, info.gsi_base + io_apic.inputs - 1);
+        io.push(io_apic).unwrap();
+    }
+
+    APICS = Some(Apics { local, io, overrides: madt.overrides, irqs: heapless::Vec::new() });
+}
+
+/// Remap the PICs out of the way of exceptions and mask all of their inputs.
+unsafe fn disable_pic() {
+    let (mut pic1_command, mut pic1_data) = (Port::<u8>::new(PIC1_COMMAND), Port::<u8>::new(PIC1_DATA));
+    let (mut pic2_command, mut pic2_data) = (Port::<u8>::new(PIC2_COMMAND), Port::<u8>::new(PIC2_DATA));
+
+    // ICW1 to ICW4: initialize, vector base, cascading on IRQ 2, 8086 mode.
+    pic1_command.write(0x11);
+    pic2_command.write(0x11);
+    pic1_data.write(PIC_VECTOR_BASE);
+    pic2_data.write(PIC_VECTOR_BASE + 8);
+    pic1_data.write(4);
+    pic2_data.write(2);
+    pic1_data.write(0x01);
+    pic2_data.write(0x01);
+
+    pic1_data.write(0xFF);
+    pic2_data.write(0xFF);
+    info!("Masked the legacy PICs.");
+}
+
+unsafe fn enable_local_apic(madt_address: PhysAddr) -> LocalApic {
+    let features = CpuId::new().get_feature_info();
+    assert!(features.as_ref().map(|it| it.has_apic()).unwrap_or(false), "CPU has no local APIC.");
+    let has_x2apic = features.map(|it| it.has_x2apic()).unwrap_or(false);
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = base_msr.read();
+    let local = if has_x2apic {
+        // Going from disabled straight to x2APIC mode is not allowed, xAPIC mode comes in between.
+        base_msr.write(base | APIC_BASE_ENABLE);
+        base_msr.write(base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        LocalApic { mode: LocalApicMode::X2Apic }
+    } else {
+        base_msr.write(base | APIC_BASE_ENABLE);
+        let phys = PhysAddr::new(base & 0x000F_FFFF_FFFF_F000);
+        if phys != madt_address {
+            warn!("Local APIC is at {:?} according to its MSR, but at {:?} according to the MADT.", phys, madt_address);
+        }
+        let registers = mmio::ioremap(phys, 0x1000, CacheMode::Uncacheable).expect("Failed to map the local APIC.");
+        LocalApic { mode: LocalApicMode::XApic(registers) }
+    };
+
+    // Accept all interrupts, mask everything local until someone needs it.
+    local.write(LAPIC_TPR, 0);
+    for lvt in [LAPIC_LVT_TIMER, LAPIC_LVT_LINT0, LAPIC_LVT_LINT1, LAPIC_LVT_ERROR].iter() {
+        local.write(*lvt, LVT_MASKED);
+    }
+    local.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    interrupts::set_handler(SPURIOUS_VECTOR, || ());
+
+    info!("Local APIC {} enabled in {} mode, version 0x{:X}.", local.id(),
+        if has_x2apic { "x2APIC" } else { "xAPIC" }, local.read(LAPIC_VERSION) & 0xFF);
+    local
+}
+
+fn apics() -> &'static mut Apics {
+    unsafe { APICS.as_mut().expect("APICs are not set up yet.") }
+}
+
+/// The local APIC of the CPU we're running on.
+pub fn local_apic() -> &'static LocalApic {
+    &apics().local
+}
+
+/// Acknowledge the interrupt being handled, see `interrupts`.
+pub fn end_of_interrupt() {
+    local_apic().end_of_interrupt();
+}
+
+/// Route the given GSI to a newly allocated vector with `handler` on it, and unmask it. Returns the
+/// vector.
+pub fn register_irq(gsi: u32, trigger: Trigger, handler: InterruptHandler) -> Result<u8, IrqError> {
+    let apics = apics();
+    let destination = apics.local.id();
+    if destination > REDIRECTION_MAX_DESTINATION {
+        warn!("Can't route GSI {} to local APIC {}, its ID is too large without interrupt remapping.", gsi, destination);
+        return Err(IrqError::UnreachableApic);
+    }
+    let io_apic = apics.io.iter().find(|it| it.handles(gsi)).ok_or(IrqError::NoSuchGsi)?;
+    if apics.irqs.iter().any(|it| it.gsi == gsi) { return Err(IrqError::InUse); }
+    if apics.irqs.len() == apics.irqs.capacity() { return Err(IrqError::OutOfVectors); }
+
+    let vector = interrupts::allocate_vector(handler).ok_or(IrqError::OutOfVectors)?;
+    apics.irqs.push(Irq { gsi, vector }).unwrap();
+    io_apic.set_redirection(gsi, vector as u64 | trigger.redirection_bits() | (destination as u64) << 56);
+    info!("GSI {} is on vector 0x{:X}, {:?}.", gsi, vector, trigger);
+    Ok(vector)
+}
+
+/// Register a handler for a legacy ISA IRQ, see `register_irq`.
+pub fn register_isa_irq(irq: u8, handler: InterruptHandler) -> Result<u8, IrqError> {
+    let (gsi, trigger) = isa_irq_to_gsi(irq);
+    register_irq(gsi, trigger, handler)
+}
+
+/// GSI an ISA IRQ is wired to and how it is triggered, following the interrupt source overrides.
+pub fn isa_irq_to_gsi(irq: u8) -> (u32, Trigger) {
+    match apics().overrides.iter().find(|it| it.irq == irq) {
+        Some(entry) => (entry.gsi, Trigger::from_inti_flags(entry.flags)),
+        None => (irq as u32, Trigger::EdgeActiveHigh),
+    }
+}
+
+/// Mask the GSI, unregister its handler and free its vector.
+pub fn unregister_irq(gsi: u32) {
+    let apics = apics();
+    let index = apics.irqs.iter().position(|it| it.gsi == gsi).expect("No handler registered for the GSI.");
+    let irq = apics.irqs.swap_remove(index);
+    mask_irq(gsi);
+    interrupts::free_vector(irq.vector);
+}
+
+pub fn mask_irq(gsi: u32) {
+    set_masked(gsi, true);
+}
+
+pub fn unmask_irq(gsi: u32) {
+    set_masked(gsi, false);
+}
+
+fn set_masked(gsi: u32, masked: bool) {
+    let io_apic = apics().io.iter().find(|it| it.handles(gsi)).expect("No I/O APIC handles the GSI.");
+    let entry = io_apic.redirection(gsi);
+    io_apic.set_redirection(gsi, if masked { entry | REDIRECTION_MASKED } else { entry & !REDIRECTION_MASKED });
+}
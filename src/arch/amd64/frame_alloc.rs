@@ -0,0 +1,360 @@
+use uefi::table::boot::{MemoryType, MemoryDescriptor};
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use x86_64::structures::paging::frame::{PhysFrame, PhysFrameRange};
+use x86_64::structures::paging::page::{PageSize as PageSizeT, Size4KiB, Size2MiB, Size1GiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, UnusedPhysFrame};
+use x86_64::structures::DescriptorTablePointer;
+
+use log::{debug, info, warn, error};
+
+use byte_unit::*;
+
+use super::memory;
+
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: u64 = 64;
+
+static mut FRAME_ALLOCATOR: Option<BitmapFrameAllocator> = None;
+
+/// Bitmap based physical frame allocator.
+///
+/// Every 4 KiB frame of physical memory below `frame_count * 4096` is represented by a single
+/// bit, a set bit meaning that the frame is in use (or does not exist at all). The bitmap itself
+/// is carved out of the first conventional memory region that is large enough to hold it.
+pub struct BitmapFrameAllocator {
+    bitmap: *mut u64,
+    bitmap_phys: PhysAddr,
+    words: usize,
+    /// Amount of frames covered by the bitmap.
+    frame_count: u64,
+    /// Amount of frames that were ever handed to this allocator.
+    total_frames: u64,
+    free_frames: u64,
+    /// Word index where the next single frame search starts.
+    next: usize,
+}
+
+/// Memory types which may ever end up being managed by the frame allocator, either right away or
+/// after being reclaimed.
+fn is_manageable(ty: MemoryType) -> bool {
+    match ty {
+        MemoryType::CONVENTIONAL => true,
+        MemoryType::LOADER_CODE => true,
+        MemoryType::LOADER_DATA => true,
+        MemoryType::BOOT_SERVICES_CODE => true,
+        MemoryType::BOOT_SERVICES_DATA => true,
+        MemoryType::ACPI_RECLAIM => true,
+        _ => false,
+    }
+}
+
+impl BitmapFrameAllocator {
+    /// Create a new frame allocator managing all conventional memory in the given memory map.
+    ///
+    /// Unsafe because the caller must guarantee that all conventional memory is actually unused
+    /// and identity mapped.
+    pub unsafe fn new<'a, I>(descriptors: I) -> Self where I: Iterator<Item = &'a MemoryDescriptor> + Clone {
+        let limit = descriptors.clone()
+            .filter(|it| is_manageable(it.ty))
+            .map(|it| it.phys_start.checked_add(it.page_count.checked_mul(FRAME_SIZE).unwrap()).unwrap())
+            .max()
+            .expect("No usable memory found in memory map.");
+
+        let frame_count = limit / FRAME_SIZE;
+        let words = ((frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD) as usize;
+        let bitmap_size = (words as u64) * 8;
+        let bitmap_frames = (bitmap_size + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // Never place the bitmap in the very first frame, so that a null pointer stays a null
+        // pointer.
+        let bitmap_phys = descriptors.clone()
+            .filter(|it| it.ty == MemoryType::CONVENTIONAL)
+            .filter_map(|it| {
+                let start = if it.phys_start == 0 { FRAME_SIZE } else { it.phys_start };
+                let end = it.phys_start + it.page_count * FRAME_SIZE;
+                if end > start && (end - start) / FRAME_SIZE >= bitmap_frames { Some(start) } else { None }
+            })
+            .next()
+            .expect("No conventional memory region is large enough to hold the frame bitmap.");
+
+        let bitmap = bitmap_phys as *mut u64;
+        for i in 0..words {
+            bitmap.add(i).write(u64::max_value());
+        }
+
+        let mut allocator = Self {
+            bitmap,
+            bitmap_phys: PhysAddr::new(bitmap_phys),
+            words,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next: 0,
+        };
+
+        for desc in descriptors.filter(|it| it.ty == MemoryType::CONVENTIONAL) {
+            allocator.add_free_range(PhysAddr::new(desc.phys_start), desc.page_count);
+        }
+        allocator.reserve(0, 1);
+        allocator.reserve(bitmap_phys / FRAME_SIZE, bitmap_frames);
+
+        info!("Frame allocator manages {} frames ({} free), bitmap of {} at 0x{:X}",
+            allocator.total_frames,
+            allocator.free_frames,
+            Byte::from_bytes(bitmap_size as u128).get_appropriate_unit(true),
+            bitmap_phys);
+
+        allocator
+    }
+
+    /// Access the bitmap through the mapping of all physical memory at `offset` instead of the
+    /// identity mapping, which is about to go away.
+    pub unsafe fn set_phys_offset(&mut self, offset: VirtAddr) {
+        self.bitmap = (offset + self.bitmap_phys.as_u64()).as_mut_ptr();
+    }
+
+    /// Hand the given range of physical memory to the allocator, marking it as free.
+    ///
+    /// Frames outside of the range covered by the bitmap are silently ignored.
+    pub unsafe fn add_free_range(&mut self, start: PhysAddr, frames: u64) {
+        assert!(start.is_aligned(FRAME_SIZE));
+        let first = start.as_u64() / FRAME_SIZE;
+        let end = core::cmp::min(first.saturating_add(frames), self.frame_count);
+        for frame in first..end {
+            if self.is_used(frame) {
+                self.clear(frame);
+                self.free_frames += 1;
+                self.total_frames += 1;
+            }
+        }
+    }
+
+    /// Amount of frames that are currently free.
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    /// Amount of frames that are managed by this allocator, free or not.
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+
+    /// Length in frames of the largest run of physically contiguous free frames.
+    pub fn largest_free_run(&self) -> u64 {
+        let mut largest = 0;
+        let mut current = 0;
+        for frame in 0..self.frame_count {
+            if self.is_used(frame) {
+                current = 0;
+            } else {
+                current += 1;
+                if current > largest { largest = current; }
+            }
+        }
+        largest
+    }
+
+    /// Allocate `count` physically contiguous frames, the first of which is aligned to
+    /// `align` frames.
+    pub fn allocate_contiguous(&mut self, count: u64, align: u64) -> Option<PhysFrameRange> {
+        assert!(count > 0);
+        assert!(align.is_power_of_two());
+        if count > self.free_frames { return None; }
+
+        let mut start = 0;
+        while start.checked_add(count)? <= self.frame_count {
+            match (start..start + count).find(|frame| self.is_used(*frame)) {
+                Some(used) => start = (used + 1 + align - 1) & !(align - 1),
+                None => {
+                    self.mark_used(start, count);
+                    self.free_frames -= count;
+                    let first = PhysFrame::containing_address(PhysAddr::new(start * FRAME_SIZE));
+                    return Some(PhysFrame::range(first, first + count));
+                }
+            }
+        }
+        None
+    }
+
+    /// Free a range of frames previously obtained from `allocate_contiguous`.
+    pub fn free_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.free(frame.start_address().as_u64() / FRAME_SIZE);
+        }
+    }
+
+    fn free(&mut self, frame: u64) {
+        assert!(frame < self.frame_count, "Tried to free frame 0x{:X} which is not managed by the frame allocator", frame * FRAME_SIZE);
+        if !self.is_used(frame) {
+            panic!("Double free of physical frame 0x{:X}", frame * FRAME_SIZE);
+        }
+        self.clear(frame);
+        self.free_frames += 1;
+        let word = (frame / BITS_PER_WORD) as usize;
+        if word < self.next { self.next = word; }
+    }
+
+    fn is_used(&self, frame: u64) -> bool {
+        let word = unsafe { *self.bitmap.add((frame / BITS_PER_WORD) as usize) };
+        word & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn clear(&mut self, frame: u64) {
+        unsafe { *self.bitmap.add((frame / BITS_PER_WORD) as usize) &= !(1 << (frame % BITS_PER_WORD)); }
+    }
+
+    /// Take frames out of circulation without handing them out.
+    fn reserve(&mut self, first: u64, count: u64) {
+        for frame in first..first + count {
+            if !self.is_used(frame) {
+                self.mark_used(frame, 1);
+                self.free_frames -= 1;
+            }
+        }
+    }
+
+    fn mark_used(&mut self, first: u64, count: u64) {
+        for frame in first..first + count {
+            unsafe { *self.bitmap.add((frame / BITS_PER_WORD) as usize) |= 1 << (frame % BITS_PER_WORD); }
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
+        if self.free_frames == 0 { return None; }
+
+        for i in (self.next..self.words).chain(0..self.next) {
+            let word = unsafe { *self.bitmap.add(i) };
+            if word == u64::max_value() { continue; }
+
+            let frame = (i as u64) * BITS_PER_WORD + (!word).trailing_zeros() as u64;
+            self.mark_used(frame, 1);
+            self.free_frames -= 1;
+            self.next = i;
+            return Some(unsafe { UnusedPhysFrame::new(PhysFrame::containing_address(PhysAddr::new(frame * FRAME_SIZE))) });
+        }
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
+        let frames = Size2MiB::SIZE / FRAME_SIZE;
+        let range = self.allocate_contiguous(frames, frames)?;
+        Some(unsafe { UnusedPhysFrame::new(PhysFrame::from_start_address(range.start.start_address()).unwrap()) })
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size1GiB>> {
+        let frames = Size1GiB::SIZE / FRAME_SIZE;
+        let range = self.allocate_contiguous(frames, frames)?;
+        Some(unsafe { UnusedPhysFrame::new(PhysFrame::from_start_address(range.start.start_address()).unwrap()) })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size4KiB>) {
+        self.free(frame.start_address().as_u64() / FRAME_SIZE);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size2MiB>) {
+        let first = PhysFrame::containing_address(frame.start_address());
+        self.free_contiguous(PhysFrame::range(first, first + Size2MiB::SIZE / FRAME_SIZE));
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size1GiB>) {
+        let first = PhysFrame::containing_address(frame.start_address());
+        self.free_contiguous(PhysFrame::range(first, first + Size1GiB::SIZE / FRAME_SIZE));
+    }
+}
+
+/// Set up the global frame allocator from the UEFI memory map.
+pub unsafe fn init<'a, I>(descriptors: I) where I: Iterator<Item = &'a MemoryDescriptor> + Clone {
+    FRAME_ALLOCATOR = Some(BitmapFrameAllocator::new(descriptors));
+}
+
+/// The global frame allocator, shared by the paging code and every later subsystem.
+pub fn frame_allocator() -> &'static mut BitmapFrameAllocator {
+    unsafe { FRAME_ALLOCATOR.as_mut().expect("Frame allocator is not initialized yet.") }
+}
+
+/// Dump the state of the frame allocator over the kernel log.
+pub fn log_state() {
+    if let Some(allocator) = unsafe { FRAME_ALLOCATOR.as_ref() } {
+        info!("Physical memory: {} of {} frames free ({}), largest contiguous free run {}",
+            allocator.free_frames(),
+            allocator.total_frames(),
+            Byte::from_bytes((allocator.free_frames() * FRAME_SIZE) as u128).get_appropriate_unit(true),
+            Byte::from_bytes((allocator.largest_free_run() * FRAME_SIZE) as u128).get_appropriate_unit(true));
+    }
+}
+
+/// Whether the given memory region still holds structures the CPU is actively using, namely the
+/// stack we're running on and the loaded descriptor tables.
+///
+/// Their addresses are looked up in the active page tables, as by now they may lie anywhere in the
+/// higher half.
+fn holds_live_structures(desc: &MemoryDescriptor) -> bool {
+    let start = desc.phys_start;
+    let end = desc.phys_start + desc.page_count * FRAME_SIZE;
+    let contains = |addr: u64| addr >= start && addr < end;
+
+    let rsp: u64;
+    let mut gdtr = DescriptorTablePointer { limit: 0, base: 0 };
+    let mut idtr = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        asm!("mov %rsp, $0" : "=r" (rsp) ::: "volatile");
+        asm!("sgdt ($0)" :: "r" (&mut gdtr) : "memory" : "volatile");
+        asm!("sidt ($0)" :: "r" (&mut idtr) : "memory" : "volatile");
+    }
+
+    let is_in_region = |addr: u64| VirtAddr::try_new(addr).ok()
+        .and_then(|addr| memory::translate(memory::mapper(), addr))
+        .map(|(phys, _)| contains(phys.as_u64()))
+        .unwrap_or(false);
+    is_in_region(rsp) || is_in_region(gdtr.base) || is_in_region(idtr.base)
+}
+
+unsafe fn reclaim<'a, I>(descriptors: I, what: &str) where I: Iterator<Item = &'a MemoryDescriptor> {
+    let allocator = frame_allocator();
+    let before = allocator.free_frames();
+
+    for desc in descriptors {
+        if holds_live_structures(desc) {
+            warn!("Not reclaiming {:?} region at 0x{:X}, it is still in use.", desc.ty, desc.phys_start);
+            continue;
+        }
+        allocator.add_free_range(PhysAddr::new(desc.phys_start), desc.page_count);
+    }
+
+    let reclaimed = (allocator.free_frames() - before) * FRAME_SIZE;
+    info!("Reclaimed {} of {} memory.", Byte::from_bytes(reclaimed as u128).get_appropriate_unit(true), what);
+}
+
+/// Hand all BOOT_SERVICES_CODE and BOOT_SERVICES_DATA memory to the frame allocator.
+///
+/// Must only be called once we're running on our own page tables, as the firmware's page tables
+/// live in boot services memory as well.
+pub unsafe fn reclaim_boot_services_memory<'a, I>(descriptors: I) where I: Iterator<Item = &'a MemoryDescriptor> {
+    reclaim(descriptors.filter(|it| match it.ty {
+        MemoryType::BOOT_SERVICES_CODE => true,
+        MemoryType::BOOT_SERVICES_DATA => true,
+        _ => false,
+    }), "boot services");
+}
+
+/// Hand all ACPI_RECLAIM memory to the frame allocator.
+///
+/// Must only be called once the ACPI tables have been parsed or copied elsewhere.
+pub unsafe fn reclaim_acpi_memory<'a, I>(descriptors: I) where I: Iterator<Item = &'a MemoryDescriptor> {
+    reclaim(descriptors.filter(|it| it.ty == MemoryType::ACPI_RECLAIM), "ACPI");
+}
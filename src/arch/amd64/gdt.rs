@@ -0,0 +1,70 @@
+//! Our own GDT and TSS, replacing the ones the firmware left us with in boot services memory.
+use x86_64::PrivilegeLevel;
+use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, DescriptorFlags, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::instructions::segmentation::{set_cs, load_ss, load_ds, load_es, load_fs, load_gs};
+use x86_64::instructions::tables::load_tss;
+
+use log::info;
+
+use super::stack;
+
+/// IST entries of the TSS, for exceptions that must not run on the stack they happen on as it may
+/// be the reason they happen, or be in the middle of being switched.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Selectors of the segments in our GDT.
+///
+/// The user data segment comes right before the user code segment and the kernel data segment
+/// right after the kernel code segment, as SYSCALL and SYSRET expect.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+static mut TSS: Option<TaskStateSegment> = None;
+static mut GDT: Option<(GlobalDescriptorTable, Selectors)> = None;
+
+/// Load our GDT and TSS and reload all segment registers.
+///
+/// To be called once running in the higher half with the kernel's address space set up, as the
+/// IST stacks are allocated from it.
+pub unsafe fn init() {
+    let mut tss = TaskStateSegment::new();
+    for index in [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX].iter() {
+        tss.interrupt_stack_table[*index as usize] = stack::allocate_ist().top();
+    }
+    TSS = Some(tss);
+
+    let kernel_data_segment = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::WRITABLE;
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::UserSegment(kernel_data_segment.bits()));
+    let mut user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let mut user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(TSS.as_ref().unwrap()));
+    user_data.set_rpl(PrivilegeLevel::Ring3);
+    user_code.set_rpl(PrivilegeLevel::Ring3);
+
+    GDT = Some((gdt, Selectors { kernel_code, kernel_data, user_data, user_code, tss }));
+    GDT.as_ref().unwrap().0.load();
+
+    set_cs(kernel_code);
+    load_ss(kernel_data);
+    load_ds(kernel_data);
+    load_es(kernel_data);
+    load_fs(kernel_data);
+    load_gs(kernel_data);
+    load_tss(tss);
+    info!("Loaded our own GDT and TSS.");
+}
+
+pub fn selectors() -> &'static Selectors {
+    unsafe { &GDT.as_ref().expect("GDT is not set up yet.").1 }
+}
@@ -0,0 +1,84 @@
+//! Moving the running kernel from its identity mapped load address into the higher half.
+//!
+//! The firmware loads us wherever it likes and identity maps everything. `memory::set_up_paging`
+//! builds page tables that map loader memory (our image, the emergency heap, ...) both at its
+//! identity address and at `memory::KERNEL_OFFSET` above it. `enter` switches to those tables,
+//! fixes up the absolute addresses in our image using its base relocations and jumps to the
+//! higher half on a fresh stack. What remains of the identity mapping is then torn down by
+//! `memory::drop_identity_mapping`.
+use core::{mem, slice};
+
+use x86_64::VirtAddr;
+use x86_64::structures::paging::frame::PhysFrame;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::instructions::tables::{lgdt, lidt};
+use x86_64::structures::DescriptorTablePointer;
+
+use log::{debug, info, warn, error};
+
+use super::memory;
+use super::pe::PeImage;
+
+/// Switch to the page tables at `root`, relocate our image and call `entry` at its higher half
+/// address with the stack pointer set to `stack_top`.
+///
+/// Interrupts must be disabled, the firmware's handlers won't survive the switch.
+pub unsafe fn enter(root: PhysFrame, stack_top: VirtAddr, entry: extern "C" fn() -> !) -> ! {
+    let image = PeImage::current();
+    let offset = memory::KERNEL_OFFSET;
+    assert!(image.base() < offset, "Image at 0x{:X} is already in the higher half.", image.base());
+
+    info!("Moving image at 0x{:X} ({} bytes) to 0x{:X}..", image.base(), image.size(), image.base() + offset);
+    Cr3::write(root, Cr3Flags::empty());
+
+    // Both the identity mapping and the higher half mapping are live now, so it doesn't matter
+    // whether anything uses a pointer from before or after its relocation.
+    let mut count = 0;
+    for rva in image.relocations() {
+        let slot = (image.base() + rva as u64) as *mut u64;
+        *slot = (*slot).wrapping_add(offset);
+        count += 1;
+    }
+    debug!("Applied {} base relocations.", count);
+
+    let entry = entry as u64 + offset;
+    // Align the stack as if `entry` was called normally, it never returns.
+    asm!("mov $0, %rsp
+          xor %rbp, %rbp
+          call *$1
+          ud2"
+        :: "r" (stack_top.as_u64() & !0xF), "r" (entry) : "memory" : "volatile");
+    unreachable!();
+}
+
+/// The higher half address of an identity mapped address in loader memory, addresses that are
+/// already in the higher half are left alone.
+pub fn relocated(addr: u64) -> u64 {
+    if addr < memory::KERNEL_OFFSET { addr + memory::KERNEL_OFFSET } else { addr }
+}
+
+/// A slice in loader memory at its higher half address, see `relocated`.
+pub unsafe fn relocated_slice<T>(s: &'static [T]) -> &'static [T] {
+    slice::from_raw_parts(relocated(s.as_ptr() as u64) as *const T, s.len())
+}
+
+/// A function pointer at its higher half address, see `relocated`.
+pub unsafe fn relocated_fn<F: Copy>(f: F) -> F {
+    assert!(mem::size_of::<F>() == mem::size_of::<u64>());
+    let addr = relocated(mem::transmute_copy::<F, u64>(&f));
+    mem::transmute_copy(&addr)
+}
+
+/// Point GDTR and IDTR at the direct physical memory mapping, so the firmware's tables stay
+/// reachable until we have our own. Must be done before `memory::drop_identity_mapping`.
+pub unsafe fn move_descriptor_tables() {
+    let mut gdtr = DescriptorTablePointer { limit: 0, base: 0 };
+    let mut idtr = DescriptorTablePointer { limit: 0, base: 0 };
+    asm!("sgdt ($0)" :: "r" (&mut gdtr) : "memory" : "volatile");
+    asm!("sidt ($0)" :: "r" (&mut idtr) : "memory" : "volatile");
+
+    gdtr.base = memory::phys_to_virt(gdtr.base).as_u64();
+    idtr.base = memory::phys_to_virt(idtr.base).as_u64();
+    lgdt(&gdtr);
+    lidt(&idtr);
+}
@@ -0,0 +1,380 @@
+//! Our interrupt descriptor table and the handlers in it.
+//!
+//! Every vector enters through a small stub in assembly that saves all general purpose registers
+//! and hands them to `interrupt_handler` as an `InterruptFrame`, so that a crash can be reported
+//! with the full register state. The x87 and SSE state is saved around the handler as well. Vectors from 32 on are dispatched to handlers registered with
+//! `set_handler` or `allocate_vector`, see `apic` for routing device interrupts to them.
+use core::mem;
+
+use x86_64::VirtAddr;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+use x86_64::instructions::segmentation;
+
+use log::{debug, error, warn};
+
+use super::apic;
+use super::memory;
+use super::gdt;
+use super::pe::PeImage;
+use super::vm::{self, Usage};
+
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+const EXCEPTION_COUNT: usize = 32;
+/// Where the legacy PICs deliver to, should they fire after being masked, see `apic`.
+const PIC_VECTORS: core::ops::Range<u8> = 0x20..0x30;
+/// Vectors handed out by `allocate_vector`. The ones above are kept for local interrupts with a
+/// fixed vector, such as the spurious interrupt.
+const ALLOCATABLE_VECTORS: core::ops::Range<u8> = 0x30..0xF0;
+/// Distance between the stubs of consecutive vectors, see `interrupt_stubs`.
+const STUB_SIZE: u64 = 16;
+const MAX_BACKTRACE_DEPTH: usize = 32;
+
+const EXCEPTION_NAMES: [&str; EXCEPTION_COUNT] = [
+    "Divide error", "Debug", "Non-maskable interrupt", "Breakpoint",
+    "Overflow", "Bound range exceeded", "Invalid opcode", "Device not available",
+    "Double fault", "Coprocessor segment overrun", "Invalid TSS", "Segment not present",
+    "Stack segment fault", "General protection fault", "Page fault", "Reserved",
+    "x87 floating point exception", "Alignment check", "Machine check", "SIMD floating point exception",
+    "Virtualization exception", "Control protection exception", "Reserved", "Reserved",
+    "Reserved", "Reserved", "Reserved", "Reserved",
+    "Hypervisor injection exception", "VMM communication exception", "Security exception", "Reserved",
+];
+
+// One stub per vector, STUB_SIZE bytes apart. Vectors for which the CPU doesn't push an error code
+// get a zero pushed in its place, so that every vector leaves the same frame behind.
+//
+// Rust code is free to use the SSE registers, so `interrupt_common` saves the interrupted code's
+// x87 and SSE state with FXSAVE in a 16 byte aligned area below the frame. RBX is callee saved, and
+// holds the stack pointer to return to past it.
+global_asm!("
+    .macro interrupt_stub vector, has_error_code
+        .align 16
+        .if \\has_error_code == 0
+            pushq $0
+        .endif
+        pushq $\\vector
+        jmp interrupt_common
+    .endm
+
+    .text
+    .global interrupt_stubs
+    .align 16
+interrupt_stubs:
+    interrupt_stub 0, 0
+    interrupt_stub 1, 0
+    interrupt_stub 2, 0
+    interrupt_stub 3, 0
+    interrupt_stub 4, 0
+    interrupt_stub 5, 0
+    interrupt_stub 6, 0
+    interrupt_stub 7, 0
+    interrupt_stub 8, 1
+    interrupt_stub 9, 0
+    interrupt_stub 10, 1
+    interrupt_stub 11, 1
+    interrupt_stub 12, 1
+    interrupt_stub 13, 1
+    interrupt_stub 14, 1
+    interrupt_stub 15, 0
+    interrupt_stub 16, 0
+    interrupt_stub 17, 1
+    interrupt_stub 18, 0
+    interrupt_stub 19, 0
+    interrupt_stub 20, 0
+    interrupt_stub 21, 1
+    interrupt_stub 22, 0
+    interrupt_stub 23, 0
+    interrupt_stub 24, 0
+    interrupt_stub 25, 0
+    interrupt_stub 26, 0
+    interrupt_stub 27, 0
+    interrupt_stub 28, 0
+    interrupt_stub 29, 1
+    interrupt_stub 30, 1
+    interrupt_stub 31, 0
+    .set next_vector, 32
+    .rept 224
+        interrupt_stub next_vector, 0
+        .set next_vector, next_vector + 1
+    .endr
+
+interrupt_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, %rdi
+    movq %rsp, %rbx
+    subq $512, %rsp
+    andq $-16, %rsp
+    fxsave64 (%rsp)
+    cld
+    call interrupt_handler
+    fxrstor64 (%rsp)
+    movq %rbx, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+    addq $16, %rsp
+    iretq
+");
+
+extern "C" {
+    static interrupt_stubs: u8;
+}
+
+/// What `interrupt_common` leaves on the stack, lowest address first.
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions without one.
+    pub error_code: u64,
+    // Pushed by the CPU.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// An interrupt gate, as the CPU expects it in the IDT.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+    /// IST entry to switch stacks to, plus one, or zero not to.
+    ist: u8,
+    type_attributes: u8,
+    offset_middle: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl Gate {
+    const fn missing() -> Self {
+        Self { offset_low: 0, selector: 0, ist: 0, type_attributes: 0, offset_middle: 0, offset_high: 0, reserved: 0 }
+    }
+
+    /// A present 64-bit interrupt gate, which keeps interrupts disabled while the handler runs.
+    fn new(handler: u64, selector: u16, ist_index: Option<u16>) -> Self {
+        Self {
+            offset_low: handler as u16,
+            selector,
+            ist: ist_index.map(|it| it as u8 + 1).unwrap_or(0),
+            type_attributes: 0x8E,
+            offset_middle: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+/// Handlers for interrupts, called with interrupts disabled.
+pub type InterruptHandler = fn();
+
+static mut IDT: [Gate; 256] = [Gate::missing(); 256];
+static mut HANDLERS: [Option<InterruptHandler>; 256] = [None; 256];
+
+/// Replace the firmware's IDT by our own.
+///
+/// To be called once running in the higher half on our own GDT, as the handlers are looked up by
+/// address and code segment.
+pub unsafe fn init() {
+    let stubs = &interrupt_stubs as *const u8 as u64;
+    let selector = segmentation::cs().0;
+
+    for vector in 0..IDT.len() {
+        let ist_index = match vector {
+            2 => Some(gdt::NMI_IST_INDEX),
+            8 => Some(gdt::DOUBLE_FAULT_IST_INDEX),
+            18 => Some(gdt::MACHINE_CHECK_IST_INDEX),
+            _ => None,
+        };
+        IDT[vector] = Gate::new(stubs + vector as u64 * STUB_SIZE, selector, ist_index);
+    }
+
+    lidt(&DescriptorTablePointer {
+        limit: (mem::size_of_val(&IDT) - 1) as u16,
+        base: IDT.as_ptr() as u64,
+    });
+}
+
+/// Put `handler` on the given vector, replacing whatever was there.
+pub fn set_handler(vector: u8, handler: InterruptHandler) {
+    assert!(vector as usize >= EXCEPTION_COUNT, "Vector {} is an exception.", vector);
+    unsafe { HANDLERS[vector as usize] = Some(handler); }
+}
+
+/// Put `handler` on a free vector and return it.
+pub fn allocate_vector(handler: InterruptHandler) -> Option<u8> {
+    let vector = ALLOCATABLE_VECTORS.clone().find(|it| unsafe { HANDLERS[*it as usize].is_none() })?;
+    set_handler(vector, handler);
+    Some(vector)
+}
+
+/// Remove the handler from a vector, so that it can be allocated again.
+pub fn free_vector(vector: u8) {
+    assert!(vector as usize >= EXCEPTION_COUNT, "Vector {} is an exception.", vector);
+    unsafe { HANDLERS[vector as usize] = None; }
+}
+
+/// Called by `interrupt_common` for every interrupt. Returning resumes whatever was interrupted,
+/// which never happens for exceptions.
+#[no_mangle]
+extern "sysv64" fn interrupt_handler(frame: &mut InterruptFrame) {
+    if frame.vector as usize >= EXCEPTION_COUNT {
+        dispatch(frame.vector as u8);
+        return;
+    }
+    if frame.vector == PAGE_FAULT {
+        handle_page_fault(frame);
+    }
+    if frame.vector == DOUBLE_FAULT {
+        report_stack_overflow(frame);
+    }
+
+    let name = EXCEPTION_NAMES.get(frame.vector as usize).unwrap_or(&"Unknown exception");
+    error!("{} (vector {}, error code 0x{:X}) at 0x{:016X}", name, frame.vector, frame.error_code, frame.rip);
+    log_registers(frame);
+    log_backtrace(frame.rip, frame.rbp);
+
+    panic!("{} at 0x{:016X}", name, frame.rip);
+}
+
+/// Run the handler of a vector from 32 on and acknowledge the interrupt.
+fn dispatch(vector: u8) {
+    if PIC_VECTORS.contains(&vector) {
+        // Spurious interrupts of the masked PICs, which the local APIC knows nothing about.
+        debug!("Ignoring legacy PIC interrupt on vector 0x{:X}.", vector);
+        return;
+    }
+
+    match unsafe { HANDLERS[vector as usize] } {
+        Some(handler) => handler(),
+        None => warn!("Unexpected interrupt on vector 0x{:X}.", vector),
+    }
+    if vector != apic::SPURIOUS_VECTOR {
+        apic::end_of_interrupt();
+    }
+}
+
+/// Report everything there is to know about a page fault.
+fn handle_page_fault(frame: &InterruptFrame) {
+    let addr = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let area = vm::kernel_space().query(addr).cloned();
+
+    if let Some(area) = area {
+        if area.usage == Usage::Guard {
+            error!("Stack overflow: {:?} lies in the guard page below the stack at {:?}.", addr, area.end);
+        }
+    }
+
+    error!("Page fault at {:?} while {} it.", addr,
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) { "executing" }
+        else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) { "writing" }
+        else { "reading" });
+    error!("Error code: {:?}", error_code);
+    match area {
+        Some(area) => error!("Area: {:?}", area),
+        None => error!("Area: none, the address is not part of the kernel's address space."),
+    }
+    memory::log_page_walk(addr);
+}
+
+/// A stack overflow ends in a double fault rather than a page fault, as the CPU can't push the
+/// page fault's frame onto the stack that overflowed. CR2 still tells where it hit the guard page.
+fn report_stack_overflow(frame: &InterruptFrame) {
+    let addr = Cr2::read();
+    if let Some(area) = vm::kernel_space().query(addr) {
+        if area.usage == Usage::Guard {
+            error!("Stack overflow: {:?} lies in the guard page below the stack at {:?}, RSP was 0x{:016X}.",
+                addr, area.end, frame.rsp);
+        }
+    }
+}
+
+fn log_registers(frame: &InterruptFrame) {
+    error!("RAX={:016X} RBX={:016X} RCX={:016X} RDX={:016X}", frame.rax, frame.rbx, frame.rcx, frame.rdx);
+    error!("RSI={:016X} RDI={:016X} RBP={:016X} RSP={:016X}", frame.rsi, frame.rdi, frame.rbp, frame.rsp);
+    error!("R8 ={:016X} R9 ={:016X} R10={:016X} R11={:016X}", frame.r8, frame.r9, frame.r10, frame.r11);
+    error!("R12={:016X} R13={:016X} R14={:016X} R15={:016X}", frame.r12, frame.r13, frame.r14, frame.r15);
+    error!("RIP={:016X} RFLAGS={:016X} CS={:04X} SS={:04X}", frame.rip, frame.rflags, frame.cs, frame.ss);
+
+    let (cr3_frame, cr3_flags) = Cr3::read();
+    error!("CR0={:016X} CR2={:016X} CR3={:016X} CR4={:016X}",
+        Cr0::read_raw(), Cr2::read().as_u64(), cr3_frame.start_address().as_u64() | cr3_flags.bits(), Cr4::read_raw());
+}
+
+/// Log the return addresses on the stack by following the chain of saved frame pointers, as far
+/// as it leads through mapped memory.
+fn log_backtrace(rip: u64, rbp: u64) {
+    let image = PeImage::current();
+    let log_frame = |depth: usize, addr: u64| {
+        if addr >= image.base() && addr < image.base() + image.size() {
+            error!("\t#{:<2} 0x{:016X} (image + 0x{:X})", depth, addr, addr - image.base());
+        } else {
+            error!("\t#{:<2} 0x{:016X}", depth, addr);
+        }
+    };
+
+    error!("Backtrace:");
+    log_frame(0, rip);
+    let mut rbp = rbp;
+    for depth in 1..MAX_BACKTRACE_DEPTH {
+        let is_readable = |addr: u64| VirtAddr::try_new(addr).ok()
+            .and_then(|addr| memory::translate(memory::mapper(), addr))
+            .is_some();
+        if rbp == 0 || rbp % 8 != 0 || !is_readable(rbp) || !is_readable(rbp + 8) {
+            break;
+        }
+
+        let (saved_rbp, return_addr) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_addr == 0 { break; }
+        log_frame(depth, return_addr);
+        rbp = saved_rbp;
+    }
+}
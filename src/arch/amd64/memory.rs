@@ -5,23 +5,59 @@ use x86_64::{PhysAddr, VirtAddr};
 
 use x86_64::structures::paging::page_table::{PageTable, PageTableEntry, PageTableFlags, PageTableIndex};
 use x86_64::structures::paging::frame::{PhysFrame, PhysFrameRange, PhysFrameRangeInclusive};
-use x86_64::structures::paging::page::{PageSize as PageSizeT, Size4KiB, Page, PageRangeInclusive};
-use x86_64::structures::paging::mapper::{Mapper, OffsetPageTable, MapToError};
+use x86_64::structures::paging::page::{PageSize as PageSizeT, Size4KiB, Size2MiB, Size1GiB, Page, PageRangeInclusive};
+use x86_64::structures::paging::mapper::{Mapper, MapperAllSizes, OffsetPageTable, MapToError, TranslateResult};
 use x86_64::structures::paging::UnusedPhysFrame;
-use x86_64::structures::paging::FrameAllocator;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
 use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::instructions::tlb;
+
+use x86::cpuid::CpuId;
 
 
 use log::{debug, info, warn, error};
 
+use super::frame_alloc;
+use super::pe::PeImage;
+use super::vm::{self, Backing, Usage};
+
 use byte_unit::*;
 
-use heapless::consts::U64 as HLU64;
+use heapless::consts::U16 as HLU16;
+use heapless::consts::U512 as HLU512;
 
 use alloc::prelude::v1::*;
 use alloc::collections::*;
 
 
+/// Start of the kernel's address space, see `vm::kernel_space`.
+const KERNELLAND: u64 = 0x800000000000;
+/// Loader code and data, our own image included, are mapped this far above their physical
+/// address, see `higher_half`. This is `KERNELLAND` sign extended, as a virtual address has to be
+/// canonical to be usable.
+pub const KERNEL_OFFSET: u64 = 0xFFFF_0000_0000_0000 | KERNELLAND;
+/// Start of the kernel heap, which grows upwards from here on demand.
+pub const KERNEL_HEAP: u64 = 0x810000000000;
+pub const KERNEL_HEAP_MAX_SIZE: u64 = 0x10000000000;
+/// Memory mapped devices are mapped between here and `MAPPED_PHYS_MEMORY`.
+const MMIO_SPACE: u64 = 0x840000000000;
+/// All physical memory is mapped linearly from here on, see `phys_to_virt`.
+const MAPPED_PHYS_MEMORY: u64 = 0x880000000000;
+/// Kernel stacks are allocated from here on, see `stack`.
+const KERNEL_STACKS: u64 = 0x820000000000;
+const KERNEL_STACKS_SIZE: u64 = 0x10000000000;
+/// Changing the mappings of more pages than this at once flushes the whole TLB, rather than every
+/// page on its own.
+const TLB_FLUSH_ALL_THRESHOLD: u64 = 64;
+
+/// The kernel's page tables, available once `set_up_paging` has run.
+static mut MAPPER: Option<OffsetPageTable<'static>> = None;
+/// Memory that is only identity mapped to survive the switch to our own page tables.
+static mut IDENTITY_RANGES: Option<heapless::Vec<PhysFrameRange, HLU16>> = None;
+
+
 /*extern {
     #[link_name = "llvm.returnaddress"]
     fn return_address() -> *const u8;
@@ -29,508 +65,601 @@ use alloc::collections::*;
 
 
 #[derive(Debug, Clone, Copy)]
-enum PageSize {
+pub enum PageSize {
     Huge(),
     Large(),
     Normal(),
 }
 impl PageSize {
-    fn size(&self) -> u64 {
+    pub fn size(&self) -> u64 {
         match self {
             Self::Huge() => n_gib_bytes!(1) as u64,     // 1 GiB
             Self::Large() => n_mib_bytes!(2) as u64,    // 2 MiB
-            Self::Normal() => n_kib_bytes!(4) as u64,   // 1 KiB
+            Self::Normal() => n_kib_bytes!(4) as u64,   // 4 KiB
         }
     }
 }
 
 
-#[derive(Debug, Clone, Copy)]
-struct MyMemoryDescriptor {
-    phys_start: PhysAddr,
-    virt_start: VirtAddr,
-    page_size: PageSize,
-    page_count: u64,
-}
+/// Calculate overhead: the amount of page tables needed to map `pages` consecutive pages of the
+/// given size, assuming the mapping starts at the beginning of a table.
+/// Note that this does not include the level 4 table (root page table)
+fn page_table_usage(page_size: PageSize, pages: u64) -> u64 {
+    let tables = |entries: u64| (entries + 512-1) / 512;
+    // There is always only a single level 4 table.
 
-impl From<&MemoryDescriptor> for MyMemoryDescriptor {
-    fn from(des: &MemoryDescriptor) -> Self {
-        Self {
-            phys_start: PhysAddr::new(des.phys_start),
-            virt_start: VirtAddr::new(des.virt_start),
-            page_size: PageSize::Normal(),
-            page_count: des.page_count,
+    match page_size {
+        PageSize::Normal() => {
+            let level1_tables = tables(pages);
+            let level2_tables = tables(level1_tables);
+            level1_tables + level2_tables + tables(level2_tables)
+        }
+        PageSize::Large() => {
+            let level2_tables = tables(pages);
+            level2_tables + tables(level2_tables)
         }
+        PageSize::Huge() => tables(pages),
     }
 }
 
-/*struct PhysFrameRangeInclusiveIter<S: x86_64::structures::paging::PageSize> {
-    frame_range: PhysFrameRangeInclusive<S>,
-    cursor: u64,
+unsafe fn mut_ptable_from_addr(addr: VirtAddr) -> &'static mut PageTable {
+    &mut *(addr.as_u64() as *mut PageTable)
 }
 
-impl<S: x86_64::structures::paging::PageSize> PhysFrameRangeInclusiveIter<S> {
-    const PSIZE: u64 = S::SIZE;
-
-    fn new(frame_range: PhysFrameRangeInclusive<S>) -> Self {
-        assert!(frame_range.start.start_address() <= frame_range.end.start_address());
-        assert!(frame_range.end.start_address() + Self::PSIZE > frame_range.end.start_address());
-        assert!(frame_range.start.start_address() + Self::PSIZE > frame_range.start.start_address());
+/// Amount of pages of every size a mapping took.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MappingStats {
+    pub huge: u64,
+    pub large: u64,
+    pub normal: u64,
+}
 
-        Self {
-            frame_range: frame_range,
-            cursor: 0
-        }
+impl MappingStats {
+    /// Memory taken up by the page tables of the mapping, see `page_table_usage`.
+    fn overhead(&self) -> u64 {
+        (page_table_usage(PageSize::Huge(), self.huge)
+            + page_table_usage(PageSize::Large(), self.large)
+            + page_table_usage(PageSize::Normal(), self.normal)) * PageSize::Normal().size()
     }
 }
 
-impl<S: x86_64::structures::paging::PageSize> Iterator for PhysFrameRangeInclusiveIter<S> {
-    type Item = PhysFrame<S>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        info!("next! cursor: {}", self.cursor);
-        let start = self.frame_range.start;
-        let end = self.frame_range.end;
-
-        let last_used_addr = PhysAddr::new(start.start_address().as_u64().checked_add(self.cursor.checked_mul(Self::PSIZE.checked_sub(1).unwrap()).unwrap()).unwrap());
-        let last_addr = PhysAddr::new(end.start_address().as_u64().checked_add(end.size().checked_sub(1).unwrap()).unwrap());
-        let new_addr = PhysAddr::new(start.start_address().as_u64().checked_add(self.cursor.checked_mul(Self::PSIZE).unwrap()).unwrap());
-        if last_used_addr == last_addr { return None }
-
-        let new_item: PhysFrame<S> = PhysFrame::from_start_address(new_addr).unwrap();
-        self.cursor = self.cursor.checked_add(1).unwrap();
-        Some(new_item)
+impl core::ops::AddAssign for MappingStats {
+    fn add_assign(&mut self, other: Self) {
+        self.huge += other.huge;
+        self.large += other.large;
+        self.normal += other.normal;
     }
-}*/
-
-/// Calculate overhead
-/// Note that this does not include the 512 bytes of the level 4 table (root page table)
-fn page_table_usage(page_size: PageSize, pages: u64) -> u64 {
-    let level1_tables = (pages + 512-1) / 512;
-    let level2_tables = (level1_tables + 512-1) / 512;
-    let level3_tables = (level2_tables + 512-1) / 512;
-    // There is always only a single level 4 table.
+}
 
-    match page_size {
-        PageSize::Normal() => level1_tables + level2_tables + level3_tables,
-        PageSize::Large() => level2_tables + level2_tables,
-        PageSize::Huge() => level3_tables,
+impl core::fmt::Display for MappingStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{} 1 GiB, {} 2 MiB and {} 4 KiB pages, about {} of page tables",
+            self.huge, self.large, self.normal, Byte::from_bytes(self.overhead() as u128).get_appropriate_unit(true))
     }
 }
 
-// I don't like how much memory and processing power this function wastes.. it's tricky because we
-// don't have a heap.
-/// Get aligned memory frames using given page size.
-// TODO is broken
-fn get_frames<'a, I>(conventional_memory: I) -> heapless::Vec<MyMemoryDescriptor, HLU64> where I: Iterator<Item = &'a MemoryDescriptor> + Clone  {
-    let align_frame = |it: &MemoryDescriptor, page_size_e: PageSize| {
-        let frame_size = it.page_count * 4096;
-        if frame_size == 0 { return (None, frame_size); }
-
-        let page_size = page_size_e.size();
-
-        let aligned_phys_start: PhysAddr = PhysAddr::new(it.phys_start).align_up(page_size);
-        if aligned_phys_start.as_u64() < it.phys_start { return (None, frame_size); }
-
-        let new_virt_start: VirtAddr = VirtAddr::new(it.virt_start + (aligned_phys_start.as_u64() - it.phys_start));
-
-        let old_last_phys: PhysAddr = PhysAddr::new(it.phys_start + (frame_size - 1));
-
-        if aligned_phys_start >= old_last_phys { return (None, frame_size); }
-
-        let page_count: u64 = ((old_last_phys.as_u64()) - aligned_phys_start.as_u64() + 1) / page_size; // Integer rounding on purpose. TODO think this through
-        if page_count == 0 { return (None, frame_size); }
-
-        let new_last_phys: PhysAddr = PhysAddr::new(aligned_phys_start.as_u64() + (page_count * page_size - 1));
-        let _new_last_virt: VirtAddr = VirtAddr::new(new_virt_start.as_u64() + (page_count * page_size - 1));
-
-        let waste: u64 = (aligned_phys_start.as_u64() - it.phys_start) + (old_last_phys.as_u64() - new_last_phys.as_u64());
-
-
-        if page_count > 0 {
-            (Some(MyMemoryDescriptor {
-                phys_start: aligned_phys_start,
-                virt_start: new_virt_start,
-                page_size: page_size_e,
-                page_count: page_count,
-            }), waste)
-        } else {
-            (None, frame_size)
-        }
+/// Map `size` bytes of physical memory at `phys` to `virt`, every time using the largest page, up
+/// to `max_page_size`, that the alignment of both addresses and the remaining size permit.
+///
+/// 1 GiB pages are only used if the CPU supports them. If mapping fails halfway, the pages mapped
+/// so far are unmapped again, the page tables created for them are kept.
+pub unsafe fn map_range<F>(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    max_page_size: PageSize,
+    frame_allocator: &mut F) -> Result<MappingStats, MapToError<Size4KiB>>
+        where F: FrameAllocator<Size4KiB> {
+    assert!(virt.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE) && size % Size4KiB::SIZE == 0,
+        "Can't map 0x{:X} bytes at {:?} to {:?}, not page aligned", size, phys, virt);
+    let has_1gib_pages = CpuId::new().get_extended_function_info().map(|it| it.has_1gib_pages()).unwrap_or(false);
+    let page_size_at = |offset: u64| {
+        let (virt, phys) = (virt + offset, phys + offset);
+        let fits = |page_size: PageSize| page_size.size() <= max_page_size.size()
+            && virt.is_aligned(page_size.size()) && phys.is_aligned(page_size.size())
+            && size - offset >= page_size.size();
+        if has_1gib_pages && fits(PageSize::Huge()) { PageSize::Huge() }
+        else if fits(PageSize::Large()) { PageSize::Large() }
+        else { PageSize::Normal() }
     };
 
-    let huge_frames = conventional_memory.clone().map(|it| align_frame(it, PageSize::Huge()));
-    let huge_page_count: u64 = huge_frames.clone().filter_map(|it| it.0).map(|it| it.page_count).sum();
-    let huge_alignment_waste = huge_frames.clone().map(|it| it.1).sum::<u64>();
-    let huge_wasted: u64 = huge_alignment_waste + page_table_usage(PageSize::Huge(), huge_page_count) * PageSize::Normal().size();
-    let _huge_usable: u64 = huge_page_count * PageSize::Huge().size();
-
-    let large_frames = conventional_memory.clone().map(|it| align_frame(it, PageSize::Large()));
-    let large_page_count: u64 = large_frames.clone().filter_map(|it| it.0).map(|it| it.page_count).sum();
-    let large_alignment_waste = large_frames.clone().map(|it| it.1).sum::<u64>();
-    let large_wasted: u64 = large_alignment_waste + page_table_usage(PageSize::Large(), large_page_count) * PageSize::Normal().size();
-    let _large_usable: u64 = large_page_count * PageSize::Large().size();
-
-    let normal_frames: heapless::Vec<MyMemoryDescriptor, HLU64> = conventional_memory.clone().map(|it| it.into()).collect();
-    let normal_page_count: u64 = conventional_memory.map(|it| it.page_count).sum();
-    let normal_wasted = page_table_usage(PageSize::Normal(), normal_page_count) * PageSize::Normal().size();
-    let _normal_usable: u64 = normal_page_count * PageSize::Normal().size();
-
-    let (recommended_page_size, final_page_count, waste) = if large_wasted < huge_wasted && large_wasted < normal_wasted { (PageSize::Large(), large_page_count, large_wasted) }
-                                else if huge_wasted < large_wasted && huge_wasted < normal_wasted { (PageSize::Huge(), huge_page_count, huge_wasted) }
-                                else if normal_wasted < huge_wasted && normal_wasted < large_wasted { (PageSize::Normal(), normal_page_count, normal_wasted) }
-                                else { (PageSize::Large(), large_page_count, large_wasted) }; // If all page sizes waste the exact same amount of memory.
-
-    let usable_conventional_memory: u64 = final_page_count * recommended_page_size.size();
-
-    info!("Recommended page size: {:?}, wasting {} and leaving {} of usable conventional memory",
-        recommended_page_size,
-        Byte::from_bytes(waste as u128).get_appropriate_unit(true),
-        Byte::from_bytes(usable_conventional_memory as u128).get_appropriate_unit(true));
-
-    fn print_waste(page_size: PageSize, waste: u64) { info!("\t{:?} would waste {} bytes", page_size, Byte::from_bytes(waste as u128).get_appropriate_unit(true)); }
-    print_waste(PageSize::Huge(), huge_wasted);
-    print_waste(PageSize::Large(), large_wasted);
-    print_waste(PageSize::Normal(), normal_wasted);
+    let mut stats = MappingStats::default();
+    let mut offset = 0;
+    while offset < size {
+        let (page_virt, page_phys, page_size) = (virt + offset, phys + offset, page_size_at(offset));
+        let result = match page_size {
+            PageSize::Huge() => map_page::<Size1GiB, _, F>(mapper, page_virt, page_phys, flags, frame_allocator),
+            PageSize::Large() => map_page::<Size2MiB, _, F>(mapper, page_virt, page_phys, flags, frame_allocator),
+            PageSize::Normal() => map_page::<Size4KiB, _, F>(mapper, page_virt, page_phys, flags, frame_allocator),
+        };
+        if let Err(err) = result {
+            // Same page sizes as on the way here, so this unmaps exactly what was mapped.
+            let mut undo = 0;
+            while undo < offset {
+                let page_virt = virt + undo;
+                let page_size = page_size_at(undo);
+                match page_size {
+                    PageSize::Huge() => unmap_page_only::<Size1GiB, _>(mapper, page_virt),
+                    PageSize::Large() => unmap_page_only::<Size2MiB, _>(mapper, page_virt),
+                    PageSize::Normal() => unmap_page_only::<Size4KiB, _>(mapper, page_virt),
+                }
+                undo += page_size.size();
+            }
+            return Err(err);
+        }
 
+        match page_size {
+            PageSize::Huge() => stats.huge += 1,
+            PageSize::Large() => stats.large += 1,
+            PageSize::Normal() => stats.normal += 1,
+        }
+        offset += page_size.size();
+    }
+    Ok(stats)
+}
 
+/// Map a single page for `map_range`, with the error in terms of 4 KiB pages whatever the size.
+unsafe fn map_page<S, M, F>(
+    mapper: &mut M,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+    frame_allocator: &mut F) -> Result<(), MapToError<Size4KiB>>
+        where   S: PageSizeT,
+                M: Mapper<S>,
+                F: FrameAllocator<Size4KiB> {
+    let frame = UnusedPhysFrame::new(PhysFrame::<S>::from_start_address(phys).unwrap());
+    match mapper.map_to(Page::<S>::from_start_address(virt).unwrap(), frame, flags, frame_allocator) {
+        Ok(flush) => { flush.ignore(); Ok(()) }
+        Err(MapToError::FrameAllocationFailed) => Err(MapToError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) => Err(MapToError::ParentEntryHugePage),
+        Err(MapToError::PageAlreadyMapped(_)) =>
+            Err(MapToError::PageAlreadyMapped(UnusedPhysFrame::new(PhysFrame::containing_address(phys)))),
+    }
+}
 
-    let frames: heapless::Vec<MyMemoryDescriptor, HLU64> = match recommended_page_size {
-        PageSize::Huge() => huge_frames.clone().map(|it| it.0.as_ref().cloned()).filter_map(|it| it).collect(),
-        PageSize::Large() => large_frames.clone().map(|it| it.0.as_ref().cloned()).filter_map(|it| it).collect(),
-        PageSize::Normal() => normal_frames,
-    };
-    frames
+/// Undo `map_page`, leaving the frame alone as `map_range` never owns it.
+unsafe fn unmap_page_only<S, M>(mapper: &mut M, virt: VirtAddr)
+        where   S: PageSizeT,
+                M: Mapper<S> {
+    let (_, flush) = mapper.unmap(Page::<S>::from_start_address(virt).unwrap()).unwrap();
+    flush.flush();
 }
 
-unsafe fn mut_ptable_from_addr(addr: VirtAddr) -> &'static mut PageTable {
-    &mut *(addr.as_u64() as *mut PageTable)
+/// Physical address `addr` is mapped to and the size of the page mapping it, if it is mapped.
+pub fn translate(mapper: &OffsetPageTable, addr: VirtAddr) -> Option<(PhysAddr, PageSize)> {
+    match mapper.translate(addr) {
+        TranslateResult::Frame4KiB { frame, offset } => Some((frame.start_address() + offset, PageSize::Normal())),
+        TranslateResult::Frame2MiB { frame, offset } => Some((frame.start_address() + offset, PageSize::Large())),
+        TranslateResult::Frame1GiB { frame, offset } => Some((frame.start_address() + offset, PageSize::Huge())),
+        TranslateResult::PageNotMapped => None,
+        TranslateResult::InvalidFrameAddress(phys) => panic!("{:?} is mapped to invalid frame {:?}", addr, phys),
+    }
 }
 
+/// Unmap `size` bytes at `virt`, whatever the size of the pages mapping them. Pages that are not
+/// mapped are skipped. Frames are returned to the frame allocator if `free_frames` is set, page
+/// tables that end up empty always are.
+///
+/// `mapper` has to be the active page tables, accessed through the mapping of all physical memory.
+/// The range may not start or end in the middle of a large page.
+pub unsafe fn unmap_range(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    size: u64,
+    free_frames: bool,
+    frame_allocator: &mut frame_alloc::BitmapFrameAllocator) {
+    let end = virt + size;
+    for_each_page(mapper, virt, end, |mapper, addr, page_size| match page_size {
+        PageSize::Normal() => unmap_page::<Size4KiB, _>(mapper, addr, free_frames, frame_allocator),
+        PageSize::Large() => unmap_page::<Size2MiB, _>(mapper, addr, free_frames, frame_allocator),
+        PageSize::Huge() => unmap_page::<Size1GiB, _>(mapper, addr, free_frames, frame_allocator),
+    });
 
-// Note that lookup is in the following order: lvl 4 table -> lvl 3 table -> lvl 2 table ->
-// lvl 1 table -> mapped page
-unsafe fn mmap<'a, F: FnMut() -> Result<PhysAddr, u32>, C: FnMut(PhysAddr) -> VirtAddr>(
-    mut alloc_page: F,
-    mut phys2virt: C,
-    root_page_table: &mut PageTable,
-    virt_start: VirtAddr,
-    phys_start: PhysAddr,
-    page_count: u64,
-    page_size: PageSize,
-    flags: PageTableFlags) {
-
-    info!("mmap mapping {} pages to {:?}", page_count, virt_start);
-    let mut table_flags = flags;
-
-    for page in 0..page_count {
-        let offset: usize = (page * page_size.size()) as usize;
-        let page_virt_start = virt_start + offset;
-        let page_phys_start = phys_start + offset;
-
-        let p4i = page_virt_start.p4_index();
-        let p3i = page_virt_start.p3_index();
-        let p2i = page_virt_start.p2_index();
-        let p1i = page_virt_start.p1_index();
-
-        if let PageSize::Huge() | PageSize::Large() = page_size {
-            table_flags.insert(PageTableFlags::HUGE_PAGE);
-        }
+    let (root, _) = Cr3::read();
+    let root_table = &mut *phys_to_virt(root.start_address().as_u64()).as_mut_ptr::<PageTable>();
+    free_empty_tables(root_table, 4, virt.as_u64(), end.as_u64(), frame_allocator);
+    flush_range(virt, end);
+}
 
-        let entry = match page_size {
-            PageSize::Huge() => {
-                let p4e = &mut root_page_table[p4i];
-                if p4e.is_unused() {
-                    let new_page = alloc_page().unwrap();
-                    mut_ptable_from_addr(phys2virt(new_page)).zero();
-                    p4e.set_addr(new_page, table_flags);
-                }
+/// Change the flags of every mapped page in `size` bytes at `virt`, whatever its size. Pages that
+/// are not mapped are skipped.
+///
+/// `mapper` has to be the active page tables. The range may not start or end in the middle of a
+/// large page.
+pub unsafe fn protect_range(mapper: &mut OffsetPageTable, virt: VirtAddr, size: u64, flags: PageTableFlags) {
+    let end = virt + size;
+    for_each_page(mapper, virt, end, |mapper, addr, page_size| match page_size {
+        PageSize::Normal() => Mapper::<Size4KiB>::update_flags(mapper, Page::from_start_address(addr).unwrap(), flags).unwrap().ignore(),
+        PageSize::Large() => Mapper::<Size2MiB>::update_flags(mapper, Page::from_start_address(addr).unwrap(), flags).unwrap().ignore(),
+        PageSize::Huge() => Mapper::<Size1GiB>::update_flags(mapper, Page::from_start_address(addr).unwrap(), flags).unwrap().ignore(),
+    });
+    flush_range(virt, end);
+}
 
-                let level3_table = mut_ptable_from_addr(phys2virt(p4e.addr()));
-                let p3e = &mut level3_table[p3i];
-                p3e
+/// Call `f` with the start and size of every mapped page in `start..end`.
+unsafe fn for_each_page<F>(mapper: &mut OffsetPageTable, start: VirtAddr, end: VirtAddr, mut f: F)
+        where F: FnMut(&mut OffsetPageTable, VirtAddr, PageSize) {
+    assert!(start.is_aligned(Size4KiB::SIZE) && end.is_aligned(Size4KiB::SIZE),
+        "Range {:?}-{:?} is not page aligned", start, end);
+    let mut addr = start;
+    while addr < end {
+        match translate(mapper, addr) {
+            Some((_, page_size)) => {
+                assert!(addr.is_aligned(page_size.size()) && end - addr >= page_size.size(),
+                    "Range {:?}-{:?} splits the {:?} page at {:?}", start, end, page_size, addr.align_down(page_size.size()));
+                f(mapper, addr, page_size);
+                addr += page_size.size();
             }
+            None => addr += Size4KiB::SIZE,
+        }
+    }
+}
 
-            PageSize::Large() =>  {
-                let p4e = &mut root_page_table[p4i];
-                if p4e.is_unused() {
-                    let new_page = alloc_page().unwrap();
-                    mut_ptable_from_addr(phys2virt(new_page)).zero();
-                    p4e.set_addr(new_page, table_flags);
-                }
-
-                let level3_table = mut_ptable_from_addr(phys2virt(p4e.addr()));
-                let p3e = &mut level3_table[p3i];
-                if p3e.is_unused() {
-                    let new_page = alloc_page().unwrap();
-                    mut_ptable_from_addr(phys2virt(new_page)).zero();
-                    p3e.set_addr(new_page, table_flags);
-                }
+unsafe fn unmap_page<S, M>(mapper: &mut M, addr: VirtAddr, free_frame: bool, frame_allocator: &mut frame_alloc::BitmapFrameAllocator)
+        where   S: PageSizeT,
+                M: Mapper<S>,
+                frame_alloc::BitmapFrameAllocator: FrameDeallocator<S> {
+    let (frame, flush) = mapper.unmap(Page::from_start_address(addr).unwrap()).unwrap();
+    flush.ignore();
+    if free_frame {
+        frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+    }
+}
 
-                let level2_table = mut_ptable_from_addr(phys2virt(p3e.addr()));
-                let p2e = &mut level2_table[p2i];
-                p2e
+/// Free the page tables below `table`, which sits at `level` (4 being the root), that no longer
+/// map anything, as far as they cover `start..end`. Returns whether `table` itself is empty.
+unsafe fn free_empty_tables(
+    table: &mut PageTable,
+    level: u64,
+    start: u64,
+    end: u64,
+    frame_allocator: &mut frame_alloc::BitmapFrameAllocator) -> bool {
+    let entry_size = Size4KiB::SIZE << (9 * (level - 1));
+    let mut addr = start;
+    while addr < end {
+        let next = (addr & !(entry_size - 1)).checked_add(entry_size);
+        let entry = &mut table[((addr / entry_size) % 512) as usize];
+        let flags = entry.flags();
+
+        if level > 1 && flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let child = &mut *phys_to_virt(entry.addr().as_u64()).as_mut_ptr::<PageTable>();
+            let child_end = next.map(|next| core::cmp::min(next, end)).unwrap_or(end);
+            if free_empty_tables(child, level - 1, addr, child_end, frame_allocator) {
+                let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+                entry.set_unused();
+                FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, UnusedPhysFrame::new(frame));
             }
+        }
 
-            PageSize::Normal() => {
-                let p4e = &mut root_page_table[p4i];
-                if p4e.is_unused() {
-                    let new_page = alloc_page().unwrap();
-                    mut_ptable_from_addr(phys2virt(new_page)).zero();
-                    p4e.set_addr(new_page, table_flags);
-                }
-
-                let level3_table = mut_ptable_from_addr(phys2virt(p4e.addr()));
-                let p3e = &mut level3_table[p3i];
-                if p3e.is_unused() {
-                    let new_page = alloc_page().unwrap();
-                    mut_ptable_from_addr(phys2virt(new_page)).zero();
-                    p3e.set_addr(new_page, table_flags);
-                }
-
-                let level2_table = mut_ptable_from_addr(phys2virt(p3e.addr()));
-                let p2e = &mut level2_table[p2i];
-                if p2e.is_unused() {
-                    let new_page = alloc_page().unwrap();
-                    mut_ptable_from_addr(phys2virt(new_page)).zero();
-                    p2e.set_addr(new_page, table_flags);
-                }
-
-                let level1_table = mut_ptable_from_addr(phys2virt(p2e.addr()));
-                let p1e = &mut level1_table[p1i];
-                p1e
-            }
-        };
-        entry.set_addr(page_phys_start, flags);
+        match next {
+            Some(next) => addr = next,
+            None => break,
+        }
     }
+    table.iter().all(|it| it.is_unused())
 }
 
-#[inline(never)]
-unsafe fn remap(root_page_table: PhysAddr) {
-    let frame: PhysFrame<Size4KiB> = PhysFrame::from_start_address(root_page_table).unwrap();
-    //info!("Remap was called with return address = {:?}", VirtAddr::from_ptr(return_address()));
-    Cr3::write(frame, Cr3Flags::empty());
-    // Do we now need to jump for our dear life.. lest we get a pagefault?
-    // ...yes we do
+/// Get rid of stale TLB entries for `start..end` after its mappings changed.
+///
+/// This only covers the TLB of the CPU we're running on. Once other CPUs run they will have to be
+/// sent an IPI to do the same.
+fn flush_range(start: VirtAddr, end: VirtAddr) {
+    if (end - start) / Size4KiB::SIZE > TLB_FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        let mut addr = start;
+        while addr < end {
+            tlb::flush(addr);
+            addr += Size4KiB::SIZE;
+        }
+    }
 }
 
-
-struct PhysFrameAllocator<I: Iterator<Item = UnusedPhysFrame>> {
-    iter: I
+fn desc_frames(descriptor: &MemoryDescriptor) -> PhysFrameRange {
+    let start: PhysFrame<Size4KiB> = PhysFrame::from_start_address(PhysAddr::new(descriptor.phys_start)).unwrap();
+    PhysFrame::range(start, start + descriptor.page_count)
 }
 
-impl<I: Iterator<Item = UnusedPhysFrame>> PhysFrameAllocator<I> {
-    fn new(iter: I) -> Self {
-        Self { iter }
+/// Whether memory of the given type is part of the mapping of all physical memory at
+/// `MAPPED_PHYS_MEMORY`.
+fn is_direct_mapped(ty: MemoryType) -> bool {
+    match ty {
+        MemoryType::RESERVED => false,
+        MemoryType::UNUSABLE => false,
+        MemoryType::MMIO => false,
+        MemoryType::MMIO_PORT_SPACE => false,
+        _ => true,
     }
 }
 
-unsafe impl<I: Iterator<Item = UnusedPhysFrame>> FrameAllocator<Size4KiB> for PhysFrameAllocator<I> {
-    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
-        unsafe { self.iter.next() }
+/// Map all physical memory `is_direct_mapped` accepts linearly at `MAPPED_PHYS_MEMORY`, using the
+/// largest pages possible.
+unsafe fn map_physical_memory<'a, I>(
+    mapper: &mut OffsetPageTable,
+    descriptors: I,
+    frame_allocator: &mut frame_alloc::BitmapFrameAllocator)
+        where I: Iterator<Item = &'a MemoryDescriptor> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let mut regions: heapless::Vec<(u64, u64), HLU512> = heapless::Vec::new();
+    for desc in descriptors.filter(|it| is_direct_mapped(it.ty)) {
+        regions.push((desc.phys_start, desc.phys_start + desc.page_count * Size4KiB::SIZE))
+            .expect("Too many memory regions to map.");
     }
-}
 
-
-unsafe fn frames_map_to<I, M, S, F>(
-    mapper: &mut M,
-    start: Page<S>,
-    frames: I,
-    flags: PageTableFlags,
-    frame_allocator: &mut F) -> PageRangeInclusive<S>
-        where   I: Iterator<Item = UnusedPhysFrame<S>>,
-                S: x86_64::structures::paging::PageSize + core::fmt::Debug,
-                M: Mapper<S>,
-                F: FrameAllocator<Size4KiB> {
-    let mut peekable = frames.peekable();
-
-    let mut cursor = start;
-    loop {
-        let frame = match peekable.next() {
-            Some(v) => v,
-            None => break
-        };
-
-        mapper.map_to(cursor, frame, flags, frame_allocator).unwrap();
-
-        if peekable.peek().is_some() {
-            cursor = Page::from_start_address(VirtAddr::new(cursor.start_address().as_u64().checked_add(S::SIZE).unwrap())).unwrap();
+    // Merge regions that touch, so that large pages can span them.
+    regions.sort_unstable_by_key(|it| it.0);
+    let mut merged: heapless::Vec<(u64, u64), HLU512> = heapless::Vec::new();
+    for (start, end) in regions.iter().cloned() {
+        match merged.last_mut() {
+            Some(last) if last.1 >= start => last.1 = core::cmp::max(last.1, end),
+            _ => merged.push((start, end)).unwrap(),
         }
     }
-    PageRangeInclusive {
-        start: start,
-        end: cursor
-    }
-}
 
-unsafe fn ranges_map_to<I, M, S, F>(
-    mapper: &mut M,
-    start: Page<S>,
-    ranges: I,
-    flags: PageTableFlags,
-    frame_allocator: &mut F) -> PageRangeInclusive<S>
-        where  I: Iterator<Item = PhysFrameRangeInclusive<S>>,
-        S: x86_64::structures::paging::PageSize + core::fmt::Debug,
-        M: Mapper<S>,
-        F: FrameAllocator<Size4KiB> {
-    let mut peekable = ranges.peekable();
-    assert!(peekable.peek().is_some());
-
-    let mut end = start;
-    for range in peekable {
-        end = range_map_to(mapper, next_page(end), range, flags, frame_allocator).end;
+    let mut stats = MappingStats::default();
+    for (start, end) in merged.iter().cloned() {
+        stats += vm::kernel_space()
+            .map(mapper, frame_allocator, phys_to_virt(start), end - start, flags, Backing::Physical(PhysAddr::new(start)), Usage::PhysicalMemory)
+            .expect("Failed to map physical memory.");
     }
-    PageRangeInclusive { start, end }
-}
-
 
-unsafe fn range_map_to<M, S, F>(
-    mapper: &mut M,
-    start: Page<S>,
-    range: PhysFrameRangeInclusive<S>,
-    flags: PageTableFlags,
-    frame_allocator: &mut F) -> PageRangeInclusive<S>
-        where  S: x86_64::structures::paging::PageSize + core::fmt::Debug,
-        M: Mapper<S>,
-        F: FrameAllocator<Size4KiB> {
-    let frames = range.map(|it| UnusedPhysFrame::new(it));
-    frames_map_to(mapper, start, frames, flags, frame_allocator)
+    let total: u64 = merged.iter().map(|it| it.1 - it.0).sum();
+    info!("Mapped {} of physical memory in {} regions using {}.",
+        Byte::from_bytes(total as u128).get_appropriate_unit(true), merged.len(), stats);
 }
 
-fn mem_desc2frame_range(descriptor: &MemoryDescriptor) -> PhysFrameRangeInclusive {
-    let start: PhysFrame<Size4KiB> = PhysFrame::from_start_address(PhysAddr::new(descriptor.phys_start)).unwrap();
-    let end: PhysFrame<Size4KiB> = PhysFrame::from_start_address(PhysAddr::new(descriptor.phys_start.checked_add(Size4KiB::SIZE.checked_mul(descriptor.page_count).unwrap()).unwrap())).unwrap();
-    PhysFrameRangeInclusive { start, end }
+/// The kernel's page tables.
+pub fn mapper() -> &'static mut OffsetPageTable<'static> {
+    unsafe { MAPPER.as_mut().expect("Paging is not set up yet.") }
 }
 
-fn next_page<S>(page: Page<S>) -> Page<S> where S: x86_64::structures::paging::PageSize {
-    let next_addr = VirtAddr::new(page.start_address().as_u64().checked_add(S::SIZE).unwrap());
-    Page::from_start_address(next_addr).unwrap()
+/// Log the page table entry of `addr` at every level, down to the one that maps it or is missing.
+pub fn log_page_walk(addr: VirtAddr) {
+    let (root, _) = Cr3::read();
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table_addr = root.start_address();
+
+    for (i, index) in indices.iter().enumerate() {
+        let level = 4 - i;
+        let table = unsafe { &*phys_to_virt(table_addr.as_u64()).as_ptr::<PageTable>() };
+        let entry = &table[*index];
+        error!("\tP{} table at {:?}, entry {:3}: {:?} {:?}", level, table_addr, u16::from(*index), entry.addr(), entry.flags());
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            error!("\t{:?} is not mapped at level {}.", addr, level);
+            return;
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            error!("\t{:?} is mapped by a {} page.", addr, if level == 3 { "1 GiB" } else { "2 MiB" });
+            return;
+        }
+        table_addr = entry.addr();
+    }
 }
 
-pub fn set_up_paging<'a, I>(mmap__: I) where I: Iterator<Item = &'a MemoryDescriptor> + Clone {
-    let KERNELLAND:         VirtAddr = VirtAddr::new(0x800000000000);
-    let MAPPED_PHYS_MEMORY: VirtAddr = VirtAddr::new(0x880000000000);
-
-    // TODO join same-properties memory descriptors that are next to eachother
-    let conventional_memory = mmap__.clone().filter(|it| it.ty == MemoryType::CONVENTIONAL);
-    let frames = get_frames(conventional_memory);
-    let get_normal_pages_phys = |frame: &MyMemoryDescriptor| {
-        let modifier = match frame.page_size {
-            PageSize::Huge() => PageSize::Huge().size() / PageSize::Normal().size(),
-            PageSize::Large() => PageSize::Large().size() / PageSize::Normal().size(),
-            PageSize::Normal() => 1,
-        };
-        let normal_page_count = frame.page_count * modifier;
-        let cloned_frame = frame.clone();
-        (0..normal_page_count).into_iter().map(move |i| cloned_frame.phys_start + i * PageSize::Normal().size())
-    };
-
-    let conventional_frames = frames.iter()
-        .map(|frame| get_normal_pages_phys(frame))
-        .flatten()
-        .map(|it| unsafe { UnusedPhysFrame::new(PhysFrame::from_start_address(it).unwrap()) });
-    let total_normal_memory = conventional_frames.clone().count() * 4096;
+/// Address of the given physical address in the mapping of all physical memory.
+pub fn phys_to_virt(phys: u64) -> VirtAddr {
+    VirtAddr::new(MAPPED_PHYS_MEMORY + phys)
+}
 
+/// Build the kernel's page tables and return their root, to be loaded by `higher_half::enter`.
+///
+/// Loader code and data are mapped both at their identity address and `KERNEL_OFFSET` above it,
+/// the identity part only lasts until `drop_identity_mapping`. All physical memory is mapped at
+/// `MAPPED_PHYS_MEMORY` and up. Everything in the higher half is tracked by `vm::kernel_space`.
+pub fn set_up_paging<'a, I>(mmap__: I) -> PhysFrame where I: Iterator<Item = &'a MemoryDescriptor> + Clone {
+    let total_conventional_memory: u64 = mmap__.clone()
+        .filter(|it| it.ty == MemoryType::CONVENTIONAL)
+        .map(|it| it.page_count * Size4KiB::SIZE)
+        .sum();
 
+    let has_nx = CpuId::new().get_extended_function_info().map(|it| it.has_execute_disable()).unwrap_or(false);
+    assert!(has_nx, "CPU does not support no-execute pages.");
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
 
-    let mut allocator = PhysFrameAllocator::new(conventional_frames.clone());
+    unsafe { frame_alloc::init(mmap__.clone()) };
+    let allocator = frame_alloc::frame_allocator();
 
     // Valid until we remap as this depends on the identity mapped memory
 
-    info!("Total available conventional memory: {}", Byte::from_bytes(total_normal_memory as u128).get_appropriate_unit(true));
+    info!("Total available conventional memory: {}", Byte::from_bytes(total_conventional_memory as u128).get_appropriate_unit(true));
 
     let phys2virt = |phys: PhysAddr| { VirtAddr::new(phys.as_u64()) };
 
-    let root_table_addr = allocator.allocate_frame().unwrap().start_address();
+    let root_table_addr = FrameAllocator::<Size4KiB>::allocate_frame(allocator).unwrap().start_address();
     let root_table: &mut PageTable = unsafe { mut_ptable_from_addr(phys2virt(root_table_addr)) };
     root_table.zero();
 
-    let mut mapper = unsafe { OffsetPageTable::new(root_table, VirtAddr::new(0)) };
+    let mapper = unsafe {
+        MAPPER = Some(OffsetPageTable::new(root_table, VirtAddr::new(0)));
+        MAPPER.as_mut().unwrap()
+    };
+
+    vm::init_kernel_space(VirtAddr::new(KERNELLAND), VirtAddr::new(0xFFFF_FFFF_FFFF_F000));
+    // The heap maps its pages itself as it grows, see grow_kernel_heap.
+    vm::kernel_space().reserve(VirtAddr::new(KERNEL_HEAP), KERNEL_HEAP_MAX_SIZE, Usage::Heap).unwrap();
+    vm::kernel_space().reserve(VirtAddr::new(KERNEL_STACKS), KERNEL_STACKS_SIZE, Usage::Stack).unwrap();
+    vm::kernel_space().reserve(VirtAddr::new(MMIO_SPACE), MAPPED_PHYS_MEMORY - MMIO_SPACE, Usage::Mmio).unwrap();
 
     let flags = {
         let mut flags = PageTableFlags::empty();
+        flags.insert(PageTableFlags::PRESENT);
         flags.insert(PageTableFlags::WRITABLE);
         flags.insert(PageTableFlags::NO_EXECUTE);
         flags
     };
 
+    // Our image stays writable until its base relocations are applied, see protect_kernel_image.
     let codeflags = {
         let mut flags = PageTableFlags::empty();
+        flags.insert(PageTableFlags::PRESENT);
+        flags.insert(PageTableFlags::WRITABLE);
         flags
     };
 
-    let mmio_memory_frames = mmap__.clone().filter(|it| it.ty == MemoryType::MMIO).map(|desc| mem_desc2frame_range(desc));
-    let mmio_port_memory_frames = mmap__.clone().filter(|it| it.ty == MemoryType::MMIO_PORT_SPACE).map(|desc| mem_desc2frame_range(desc));
+    info!("Mapping physical memory..");
+    unsafe { map_physical_memory(mapper, mmap__.clone(), allocator) };
 
-    let acpi_memory_frames = mmap__.clone().filter(|it| it.ty == MemoryType::ACPI_RECLAIM).map(|desc| mem_desc2frame_range(desc));
+    // Our stack is in loader memory as well by now, see uefi_start.
+    let mut identity: heapless::Vec<PhysFrameRange, HLU16> = heapless::Vec::new();
 
-    let kernel_code_frames = mmap__.clone().filter(|it| it.ty == MemoryType::LOADER_CODE).map(|desc| mem_desc2frame_range(desc));
-    let kernel_data_frames = mmap__.clone().filter(|it| it.ty == MemoryType::LOADER_DATA).map(|desc| mem_desc2frame_range(desc));
+    info!("Mapping kernel code and data to 0x{:X}..", KERNEL_OFFSET);
+    for desc in mmap__.clone() {
+        let frames = desc_frames(desc);
+        if desc.ty != MemoryType::LOADER_CODE && desc.ty != MemoryType::LOADER_DATA { continue; }
 
-    info!("Mapping physical memory..");
-    unsafe { frames_map_to(&mut mapper, Page::from_start_address(MAPPED_PHYS_MEMORY).unwrap(), conventional_frames.clone(), flags, &mut allocator) };
+        // Only ever 4 KiB pages, as the image's permissions are set per page and the identity
+        // mapping is taken down page by page. The identity mapping is not tracked by the kernel's
+        // address space, which only covers the higher half.
+        let (phys, size) = (PhysAddr::new(desc.phys_start), desc.page_count * Size4KiB::SIZE);
+        unsafe {
+            vm::kernel_space()
+                .map(mapper, allocator, VirtAddr::new(KERNEL_OFFSET + desc.phys_start), size, flags, Backing::Physical(phys), Usage::Loader)
+                .expect("Failed to map loader memory.");
+            map_range(mapper, VirtAddr::new(desc.phys_start), phys, size, flags, PageSize::Normal(), allocator)
+                .expect("Failed to identity map loader memory.");
+        }
+        identity.push(frames).expect("Too many identity mapped memory regions.");
+    }
 
-    let mut last;
+    let image = PeImage::current();
+    let image_pages: Page<Size4KiB> = Page::containing_address(VirtAddr::new(image.base()));
+    for i in 0..(image.size() + Size4KiB::SIZE - 1) / Size4KiB::SIZE {
+        let page = image_pages + i;
+        let higher_half: Page<Size4KiB> = Page::containing_address(page.start_address() + KERNEL_OFFSET);
+        for page in [page, higher_half].iter() {
+            unsafe { mapper.update_flags(*page, codeflags).expect("Image is not in loader memory.").ignore() };
+        }
+    }
 
-    info!("Mapping kernel code..");
-    last = unsafe { ranges_map_to(&mut mapper, Page::from_start_address(KERNELLAND).unwrap(), kernel_code_frames, codeflags, &mut allocator) }.end;
+    // We never call SetVirtualAddressMap, so the firmware expects its runtime services to stay
+    // identity mapped.
+    info!("Mapping runtime services..");
+    for desc in mmap__.clone() {
+        let flags = match desc.ty {
+            MemoryType::RUNTIME_SERVICES_CODE => codeflags,
+            MemoryType::RUNTIME_SERVICES_DATA => flags,
+            _ => continue,
+        };
+        let size = desc.page_count * Size4KiB::SIZE;
+        unsafe { map_range(mapper, VirtAddr::new(desc.phys_start), PhysAddr::new(desc.phys_start), size, flags, PageSize::Huge(), allocator) }
+            .expect("Failed to map runtime services.");
+    }
 
-    info!("Mapping kernel data to 0x{:X}..", next_page(last).start_address().as_u64());
-    last = unsafe { ranges_map_to(&mut mapper, next_page(last), kernel_data_frames, flags, &mut allocator) }.end;
+    // BOOT_SERVICES_CODE/DATA and ACPI_RECLAIM regions are handed to the frame allocator by
+    // arch::amd64::init, once we're no longer running on the firmware's page tables.
 
+    unsafe { IDENTITY_RANGES = Some(identity); }
+    PhysFrame::containing_address(root_table_addr)
+}
 
-    // Create paging tables from 0x880000000000 onwards and map all conventional memory to
-    // 0x88000000000 and onwards.
-    /*let mut cursor = MAPPED_PHYS_MEMORY;
-    info!("Mapping {} frames of physical conventional memory to 0x880000000000", frames.iter().count());
-    for frame in frames.iter() {
-        let new_virt_start = cursor;
-        unsafe {
-            info!("before mmap: frame.page_count = {}", frame.page_count);
-            mmap(
-                &mut alloc_page,
-                phys2virt,
-                root_table,
-                new_virt_start,
-                frame.phys_start,
-                frame.page_count,
-                frame.page_size,
-                flags,
-            );
-        }
-        info!("cursor: {:?}", cursor);
-        cursor = VirtAddr::new(cursor.as_u64() + frame.page_size.size() * frame.page_count);
-        info!("after cursor");
+/// Map every page of our image with the permissions of the sections in it, so that none of it is
+/// both writable and executable, and have the CPU enforce that in ring 0 as well.
+///
+/// To be called once the image runs in the higher half and its base relocations are applied.
+pub unsafe fn protect_kernel_image() {
+    let mapper = MAPPER.as_mut().unwrap();
+    let image = PeImage::current();
+    let first: Page<Size4KiB> = Page::from_start_address(VirtAddr::new(image.base())).unwrap();
+
+    for section in image.sections() {
+        debug!("Section {:8} at 0x{:X}, {} bytes, {}{}{}", section.name(), image.base() + section.rva, section.size,
+            if section.is_readable() { "r" } else { "-" },
+            if section.is_writable() { "w" } else { "-" },
+            if section.is_executable() { "x" } else { "-" });
     }
-    info!("Used {} pages for mapping all conventional memory to 0x88000000000 and onwards.", used_pages);*/
-
 
+    // Anything not covered by a section, such as the headers, is read-only data.
+    let page_flags = |i: u64| {
+        let (start, end) = (i * Size4KiB::SIZE, (i + 1) * Size4KiB::SIZE);
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        for section in image.sections().filter(|it| it.rva < end && it.rva + it.size > start) {
+            if section.is_writable() { flags.insert(PageTableFlags::WRITABLE); }
+            if section.is_executable() { flags.remove(PageTableFlags::NO_EXECUTE); }
+        }
+        flags
+    };
 
-    /*let mut alloc_page = || { let retval = MAPPED_PHYS_MEMORY + used_pages * page_size; used_pages++; retval };
-    {
-        let mut cursor = KERNELLAND;
-        for frame in kernel_code_frames {
-            unsafe {
-                mmap(
+    // Protect runs of pages with the same flags at once, every run becomes an area of its own.
+    let pages = (image.size() + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    let mut run_start = 0;
+    for i in 1..=pages {
+        let flags = page_flags(run_start);
+        if i < pages && page_flags(i) == flags { continue; }
 
-                );
-            }
+        if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+            warn!("Image pages at RVA 0x{:X} are shared by writable and executable sections.", run_start * Size4KiB::SIZE);
         }
-    }*/
+        vm::kernel_space()
+            .protect(mapper, (first + run_start).start_address(), (i - run_start) * Size4KiB::SIZE, flags)
+            .expect("Kernel image is not in loader memory.");
+        run_start = i;
+    }
 
-    // Technically the ACPI_RECLAIM regions are also reusable, but we don't reuse them right now.
-    let reuse = mmap__.clone().filter(|it| match it.ty {
-        MemoryType::BOOT_SERVICES_CODE => true,
-        MemoryType::BOOT_SERVICES_DATA => true,
-        _ => false,
-    });
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    info!("Kernel image at 0x{:X} is mapped write xor execute.", image.base());
+}
 
-    // Amount of 4KiB pages of physical memory.
-    let phys_page_count: u64 = mmap__.clone().map(|it| it.page_count).sum();
+/// Access page tables and the frame bitmap through the mapping of all physical memory, to be
+/// called first thing after entering the higher half.
+pub unsafe fn switch_to_direct_map() {
+    let offset = VirtAddr::new(MAPPED_PHYS_MEMORY);
+    let (root, _) = Cr3::read();
+    let root_table = &mut *phys_to_virt(root.start_address().as_u64()).as_mut_ptr::<PageTable>();
+    MAPPER = Some(OffsetPageTable::new(root_table, offset));
+    frame_alloc::frame_allocator().set_phys_offset(offset);
+}
 
-    unsafe {
-        info!("Prepare for virtual memory remap!");
-        remap(PhysAddr::new(root_table_addr.as_u64()));
-    };
+/// Unmap everything that was only identity mapped to survive the switch to our own page tables,
+/// along with the page tables that held it.
+pub unsafe fn drop_identity_mapping() {
+    let mapper = MAPPER.as_mut().unwrap();
+    let mut pages = 0;
+    for range in IDENTITY_RANGES.take().unwrap().iter() {
+        let (start, count) = (range.start.start_address().as_u64(), range.end - range.start);
+        unmap_range(mapper, VirtAddr::new(start), count * Size4KiB::SIZE, false, frame_alloc::frame_allocator());
+        pages += count;
+    }
+    info!("Dropped the identity mapping of {} pages.", pages);
+}
+
+/// Back `size` bytes of kernel heap starting at `start` with fresh frames, see
+/// `kernalloc::GrowFn`.
+///
+/// The heap's area in the kernel's address space stays reserved, pages are mapped in it here
+/// without going through `vm` as the heap keeps track of how far it has grown itself.
+pub unsafe fn grow_kernel_heap(start: usize, size: usize) -> bool {
+    let mapper = MAPPER.as_mut().unwrap();
+    let allocator = frame_alloc::frame_allocator();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let first: Page<Size4KiB> = Page::from_start_address(VirtAddr::new(start as u64)).unwrap();
+    let count = (size as u64) / Size4KiB::SIZE;
+
+    for i in 0..count {
+        let mapped = match FrameAllocator::<Size4KiB>::allocate_frame(allocator) {
+            Some(frame) => {
+                let phys = *frame;
+                let result = mapper.map_to(first + i, frame, flags, allocator);
+                if result.is_err() {
+                    // Mapping failed, most likely for lack of a frame for a page table, so this one
+                    // never made it into the page tables.
+                    FrameDeallocator::<Size4KiB>::deallocate_frame(allocator, UnusedPhysFrame::new(phys));
+                }
+                result.map(|flush| flush.flush()).is_ok()
+            }
+            None => false,
+        };
+
+        if !mapped {
+            // Roll back, so that the heap can try again later.
+            unmap_range(mapper, first.start_address(), i * Size4KiB::SIZE, true, allocator);
+            return false;
+        }
+    }
+    true
 }
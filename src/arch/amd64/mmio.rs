@@ -0,0 +1,97 @@
+//! Mapping memory mapped device registers and framebuffers into the MMIO window of the kernel's
+//! address space, with the caching behaviour they need.
+use x86_64::{PhysAddr, VirtAddr};
+
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::page::{PageSize as PageSizeT, Size4KiB, Size2MiB, Size1GiB};
+use x86_64::registers::model_specific::Msr;
+use x86_64::instructions::tlb;
+
+use x86::cpuid::CpuId;
+
+use log::info;
+
+use super::memory;
+use super::frame_alloc;
+use super::vm::{self, Backing, Usage, VmError};
+
+const IA32_PAT: u32 = 0x277;
+
+/// Memory types as encoded in the PAT.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+
+/// Our PAT, entry `i` being selected by page table entries with PAT, PCD and PWT making up `i`.
+///
+/// The first four entries cover every cache mode, so that the PAT bit, which moves around in
+/// large page entries, is never needed. The upper four just mirror them. Entries 0, 1 and 3 are
+/// what they are after reset, so that anything mapped before `init` keeps its meaning.
+const PAT: [u64; 8] = [PAT_WB, PAT_WT, PAT_WC, PAT_UC, PAT_WB, PAT_WT, PAT_WC, PAT_UC];
+
+/// How the CPU may cache accesses to a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    /// Reads are cached, writes go straight to memory.
+    WriteThrough,
+    /// Nothing is cached, but writes may be combined and reordered, as suits framebuffers.
+    WriteCombining,
+    /// Nothing is cached, every access goes to the device in order, as suits device registers.
+    Uncacheable,
+}
+
+impl CacheMode {
+    /// Page table flags selecting the PAT entry for this mode.
+    fn flags(&self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PageTableFlags::NO_CACHE,
+            CacheMode::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Program the PAT, see `PAT`. To be called before anything is mapped through `ioremap`.
+pub unsafe fn init() {
+    let has_pat = CpuId::new().get_feature_info().map(|it| it.has_pat()).unwrap_or(false);
+    assert!(has_pat, "CPU does not support the page attribute table.");
+
+    let value = PAT.iter().enumerate().fold(0, |value, (i, ty)| value | ty << (i * 8));
+    Msr::new(IA32_PAT).write(value);
+    // Nothing may stay cached or in the TLB under the old memory types.
+    asm!("wbinvd" ::: "memory" : "volatile");
+    tlb::flush_all();
+    info!("Programmed the PAT: 0x{:016X}", value);
+}
+
+/// Map `size` bytes of device memory at `phys` into the MMIO window, returning the address `phys`
+/// ended up at. The range doesn't have to be page aligned.
+pub unsafe fn ioremap(phys: PhysAddr, size: u64, mode: CacheMode) -> Result<VirtAddr, VmError> {
+    let start = phys.align_down(Size4KiB::SIZE);
+    let size = (phys + size).align_up(Size4KiB::SIZE) - start;
+
+    // Keep large pages possible where the range allows for them.
+    let align = [Size1GiB::SIZE, Size2MiB::SIZE].iter().cloned()
+        .find(|align| start.is_aligned(*align) && size >= *align)
+        .unwrap_or(Size4KiB::SIZE);
+
+    let space = vm::kernel_space();
+    let virt = space.find_reserved(size, align, Usage::Mmio).ok_or(VmError::OutOfAddressSpace)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | mode.flags();
+    space.map(memory::mapper(), frame_alloc::frame_allocator(), virt, size, flags, Backing::Physical(start), Usage::Mmio)?;
+    Ok(virt + (phys - start))
+}
+
+/// Undo `ioremap`, given the address it returned.
+pub unsafe fn iounmap(addr: VirtAddr) {
+    let space = vm::kernel_space();
+    let area = *space.query(addr).expect("Address was not mapped by ioremap.");
+    assert!(area.usage == Usage::Mmio && area.backing != Backing::Reserved, "{:?} was not mapped by ioremap.", addr);
+
+    space.unmap(memory::mapper(), frame_alloc::frame_allocator(), area.start, area.size()).unwrap();
+    // Hand the range back to the MMIO window.
+    space.reserve(area.start, area.size(), Usage::Mmio).unwrap();
+}
@@ -4,11 +4,79 @@ use log::{debug, info, warn, error};
 
 
 mod memory;
+mod frame_alloc;
+mod pe;
+mod higher_half;
+mod vm;
+mod gdt;
+mod interrupts;
+pub mod acpi;
+pub mod apic;
+pub mod mmio;
+pub mod rtc;
+pub mod stack;
+pub mod time;
 
-pub fn init<'a, I>(descriptors: I) where I: Iterator<Item = &'a MemoryDescriptor> + Clone {
+/// Continues booting once the architecture is set up, with the memory map.
+pub type KernelMain = fn(&'static [MemoryDescriptor]) -> !;
+
+/// What `init` hands over to `init_higher_half` across the jump.
+static mut HANDOFF: Option<(&'static [MemoryDescriptor], KernelMain)> = None;
+
+pub fn init(descriptors: &'static [MemoryDescriptor], kernel_main: KernelMain) -> ! {
     info!("Memory map:");
-    for desc in descriptors.clone() {
+    for desc in descriptors {
         info!("{:?}", desc);
     }
-    memory::set_up_paging(descriptors);
+
+    // The firmware's interrupt handlers don't survive the switch to our own page tables.
+    x86_64::instructions::interrupts::disable();
+
+    let root = memory::set_up_paging(descriptors.iter());
+    // Mapped in the higher half only, this is the stack we keep running on from there on.
+    let stack = stack::allocate(stack::KERNEL_STACK_SIZE).expect("Failed to allocate the kernel stack.");
+    unsafe {
+        HANDOFF = Some((descriptors, kernel_main));
+        higher_half::enter(root, stack.top(), init_higher_half)
+    }
+}
+
+/// Second half of `init`, running in the higher half on the kernel stack.
+extern "C" fn init_higher_half() -> ! {
+    let (descriptors, kernel_main) = unsafe {
+        memory::switch_to_direct_map();
+        crate::kernalloc::move_to_higher_half(memory::KERNEL_OFFSET as usize);
+
+        let (descriptors, kernel_main) = HANDOFF.take().unwrap();
+        let handoff = (higher_half::relocated_slice(descriptors), higher_half::relocated_fn(kernel_main));
+
+        // GDTR and IDTR still hold identity mapped addresses, which must not outlive the mapping.
+        higher_half::move_descriptor_tables();
+        memory::drop_identity_mapping();
+        memory::protect_kernel_image();
+        gdt::init();
+        interrupts::init();
+        mmio::init();
+        apic::init();
+        time::init();
+        handoff
+    };
+    info!("Running in the higher half.");
+    vm::kernel_space().log();
+
+    crate::kernalloc::register_oom_reporter(frame_alloc::log_state);
+
+    unsafe {
+        frame_alloc::reclaim_boot_services_memory(descriptors.iter());
+        // The MADT and HPET tables have been read by now, and nothing else looks at the ACPI tables.
+        frame_alloc::reclaim_acpi_memory(descriptors.iter());
+
+        let heap_start = x86_64::VirtAddr::new(memory::KERNEL_HEAP).as_u64();
+        crate::kernalloc::init_kernel_heap(heap_start as usize, memory::KERNEL_HEAP_MAX_SIZE as usize, memory::grow_kernel_heap);
+    }
+
+    // Every device interrupt is masked until a driver registers a handler for it.
+    x86_64::instructions::interrupts::enable();
+
+    kernel_main(descriptors)
 }
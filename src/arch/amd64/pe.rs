@@ -0,0 +1,197 @@
+//! Just enough of a PE/COFF parser to find our own image in memory, its sections and its base
+//! relocations.
+use core::{ptr, str};
+
+extern "C" {
+    /// Defined by the linker at the start of our image, its DOS header.
+    static __ImageBase: u8;
+}
+
+/// Offset of the PE signature offset within the DOS header.
+const E_LFANEW: usize = 0x3C;
+const PE_SIGNATURE: u32 = 0x0000_4550; // "PE\0\0"
+const COFF_HEADER_SIZE: usize = 20;
+/// Offsets within the COFF header.
+const COFF_NUMBER_OF_SECTIONS: usize = 2;
+const COFF_SIZE_OF_OPTIONAL_HEADER: usize = 16;
+const PE32_PLUS_MAGIC: u16 = 0x20B;
+
+/// Offsets within the PE32+ optional header.
+const OPT_SIZE_OF_IMAGE: usize = 56;
+const OPT_NUMBER_OF_RVA_AND_SIZES: usize = 108;
+const OPT_DATA_DIRECTORIES: usize = 112;
+
+const DIRECTORY_BASE_RELOCATION: usize = 5;
+
+const SECTION_HEADER_SIZE: usize = 40;
+/// Offsets within a section header.
+const SECTION_VIRTUAL_SIZE: usize = 8;
+const SECTION_VIRTUAL_ADDRESS: usize = 12;
+const SECTION_SIZE_OF_RAW_DATA: usize = 16;
+const SECTION_CHARACTERISTICS: usize = 36;
+
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// A PE32+ image loaded in memory, with its sections at their RVAs.
+pub struct PeImage {
+    base: *const u8,
+    /// Offset of the COFF header from `base`.
+    coff_header: usize,
+    /// Offset of the optional header from `base`.
+    optional_header: usize,
+}
+
+/// A section of a loaded image, as described by its section header.
+#[derive(Debug, Clone, Copy)]
+pub struct Section {
+    name: [u8; 8],
+    /// Address of the section relative to the image base.
+    pub rva: u64,
+    /// Size of the section in memory, which includes zero filled data such as `.bss`.
+    pub size: u64,
+    characteristics: u32,
+}
+
+impl Section {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|it| *it == 0).unwrap_or(self.name.len());
+        str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_READ != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_WRITE != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+    }
+}
+
+impl PeImage {
+    /// The image we are running from.
+    pub fn current() -> Self {
+        unsafe { Self::at(&__ImageBase as *const u8) }
+    }
+
+    /// Parse the headers of the image loaded at `base`, panicking if it is not a PE32+ image.
+    pub unsafe fn at(base: *const u8) -> Self {
+        let mut image = Self { base, coff_header: 0, optional_header: 0 };
+        let pe_header = image.read_u32(E_LFANEW) as usize;
+        assert!(image.read_u32(pe_header) == PE_SIGNATURE, "No PE image at {:?}", base);
+
+        image.coff_header = pe_header + 4;
+        image.optional_header = image.coff_header + COFF_HEADER_SIZE;
+        assert!(image.read_u16(image.optional_header) == PE32_PLUS_MAGIC, "PE image at {:?} is not PE32+", base);
+        image
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base as u64
+    }
+
+    /// Size of the image in memory, all sections included.
+    pub fn size(&self) -> u64 {
+        unsafe { self.read_u32(self.optional_header + OPT_SIZE_OF_IMAGE) as u64 }
+    }
+
+    /// All sections of the image, in the order of the section table.
+    pub fn sections<'a>(&'a self) -> impl Iterator<Item = Section> + 'a {
+        let (count, table) = unsafe {
+            (self.read_u16(self.coff_header + COFF_NUMBER_OF_SECTIONS) as usize,
+             self.optional_header + self.read_u16(self.coff_header + COFF_SIZE_OF_OPTIONAL_HEADER) as usize)
+        };
+        (0..count).map(move |i| unsafe {
+            let header = table + i * SECTION_HEADER_SIZE;
+            let mut name = [0u8; 8];
+            ptr::copy_nonoverlapping(self.base.add(header), name.as_mut_ptr(), name.len());
+
+            let virtual_size = self.read_u32(header + SECTION_VIRTUAL_SIZE) as u64;
+            Section {
+                name,
+                rva: self.read_u32(header + SECTION_VIRTUAL_ADDRESS) as u64,
+                size: if virtual_size != 0 { virtual_size } else { self.read_u32(header + SECTION_SIZE_OF_RAW_DATA) as u64 },
+                characteristics: self.read_u32(header + SECTION_CHARACTERISTICS),
+            }
+        })
+    }
+
+    /// RVA and size of the given data directory, if present.
+    fn data_directory(&self, index: usize) -> Option<(usize, usize)> {
+        unsafe {
+            if index >= self.read_u32(self.optional_header + OPT_NUMBER_OF_RVA_AND_SIZES) as usize {
+                return None;
+            }
+            let entry = self.optional_header + OPT_DATA_DIRECTORIES + index * 8;
+            match (self.read_u32(entry) as usize, self.read_u32(entry + 4) as usize) {
+                (_, 0) => None,
+                directory => Some(directory),
+            }
+        }
+    }
+
+    /// RVAs of all 64-bit absolute addresses in the image that have to be fixed up when it moves.
+    pub fn relocations(&self) -> Relocations {
+        let (start, end) = match self.data_directory(DIRECTORY_BASE_RELOCATION) {
+            Some((rva, size)) => (rva, rva + size),
+            None => (0, 0),
+        };
+        Relocations { image: self, cursor: start, end, block_rva: 0, block_end: start }
+    }
+
+    unsafe fn read_u16(&self, offset: usize) -> u16 {
+        ptr::read_unaligned(self.base.add(offset) as *const u16)
+    }
+
+    unsafe fn read_u32(&self, offset: usize) -> u32 {
+        ptr::read_unaligned(self.base.add(offset) as *const u32)
+    }
+}
+
+/// Iterator over the base relocation blocks of an image, see `PeImage::relocations`.
+pub struct Relocations<'a> {
+    image: &'a PeImage,
+    /// Offset of the next entry to read.
+    cursor: usize,
+    /// End of the base relocation directory.
+    end: usize,
+    /// Page RVA of the current block.
+    block_rva: usize,
+    block_end: usize,
+}
+
+impl<'a> Iterator for Relocations<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        unsafe {
+            loop {
+                if self.cursor >= self.block_end {
+                    // Every block starts with its page RVA and its size, header included.
+                    if self.cursor + 8 > self.end { return None; }
+                    self.block_rva = self.image.read_u32(self.cursor) as usize;
+                    self.block_end = self.cursor + self.image.read_u32(self.cursor + 4) as usize;
+                    assert!(self.block_end > self.cursor, "Corrupt base relocation block at RVA 0x{:X}", self.cursor);
+                    self.cursor += 8;
+                    continue;
+                }
+
+                let entry = self.image.read_u16(self.cursor);
+                self.cursor += 2;
+                match entry >> 12 {
+                    IMAGE_REL_BASED_ABSOLUTE => continue, // Padding
+                    IMAGE_REL_BASED_DIR64 => return Some(self.block_rva + (entry & 0xFFF) as usize),
+                    ty => panic!("Unsupported base relocation type {} at RVA 0x{:X}", ty, self.block_rva),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! The CMOS real time clock, as a fallback for when the firmware can't tell us the time.
+use x86_64::instructions::port::Port;
+
+use crate::wallclock::DateTime;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOURS_PM: u8 = 1 << 7;
+
+/// The RTC only keeps two digits of the year, and where the century register is depends on the
+/// FADT. We assume it's this one.
+const CENTURY: u16 = 2000;
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+/// The raw date and time registers, read while no update is in progress.
+fn read_registers() -> [u8; 6] {
+    while read_register(RTC_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+    let mut values = [0u8; 6];
+    for (value, reg) in values.iter_mut().zip([RTC_SECONDS, RTC_MINUTES, RTC_HOURS, RTC_DAY, RTC_MONTH, RTC_YEAR].iter()) {
+        *value = read_register(*reg);
+    }
+    values
+}
+
+/// The time the RTC keeps, which we take to be UTC.
+pub fn read() -> DateTime {
+    // An update may still start right after we checked, so read until two reads agree.
+    let mut values = read_registers();
+    loop {
+        let again = read_registers();
+        if again == values { break; }
+        values = again;
+    }
+
+    let status_b = read_register(RTC_STATUS_B);
+    let decode = |value: u8| if status_b & STATUS_B_BINARY != 0 { value } else { (value >> 4) * 10 + (value & 0x0F) };
+    let [seconds, minutes, hours, day, month, year] = values;
+
+    let mut hour = decode(hours & !HOURS_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 is midnight or noon, the PM bit tells them apart.
+        hour %= 12;
+        if hours & HOURS_PM != 0 { hour += 12; }
+    }
+
+    DateTime {
+        year: CENTURY + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minutes),
+        second: decode(seconds),
+        nanosecond: 0,
+    }
+}
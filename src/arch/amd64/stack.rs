@@ -0,0 +1,87 @@
+//! Kernel stacks, each with an unmapped guard page below it, allocated from their own region of
+//! the kernel's address space.
+use x86_64::VirtAddr;
+
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::page::{PageSize as PageSizeT, Size4KiB};
+
+use super::memory;
+use super::frame_alloc;
+use super::vm::{self, Backing, Usage, VmError};
+
+/// Size of the stack the kernel runs on once in the higher half, and of thread stacks.
+pub const KERNEL_STACK_SIZE: u64 = 64 * 1024;
+/// Size of the stacks the CPU switches to through the IST, for exceptions that can't trust the
+/// stack they happen on.
+pub const IST_STACK_SIZE: u64 = 16 * 1024;
+const GUARD_SIZE: u64 = Size4KiB::SIZE;
+
+/// A stack in the kernel's stack region, growing down from `top` to `bottom`.
+#[derive(Debug)]
+pub struct KernelStack {
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Initial stack pointer, 16 byte aligned.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+}
+
+/// Allocate a stack of `size` bytes, rounded up to whole pages, with a guard page below it.
+pub fn allocate(size: u64) -> Result<KernelStack, VmError> {
+    let size = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE * Size4KiB::SIZE;
+    let space = vm::kernel_space();
+    let (mapper, allocator) = (memory::mapper(), frame_alloc::frame_allocator());
+
+    let guard = space.find_reserved(GUARD_SIZE + size, Size4KiB::SIZE, Usage::Stack).ok_or(VmError::OutOfAddressSpace)?;
+    let bottom = guard + GUARD_SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe {
+        space.map(mapper, allocator, guard, GUARD_SIZE, PageTableFlags::empty(), Backing::Reserved, Usage::Guard)?;
+        if let Err(err) = space.map(mapper, allocator, bottom, size, flags, Backing::Anonymous, Usage::Stack) {
+            release(guard, GUARD_SIZE);
+            return Err(err);
+        }
+    }
+    Ok(KernelStack { bottom, top: bottom + size })
+}
+
+/// Allocate a stack for an IST entry of the TSS.
+pub fn allocate_ist() -> KernelStack {
+    allocate(IST_STACK_SIZE).expect("Failed to allocate an IST stack.")
+}
+
+/// Free a stack and its guard page. Nothing may be running on it any more.
+pub unsafe fn free(stack: KernelStack) {
+    release(stack.bottom - GUARD_SIZE, stack.size() + GUARD_SIZE);
+}
+
+/// Unmap part of the stack region and hand it back.
+unsafe fn release(start: VirtAddr, size: u64) {
+    let space = vm::kernel_space();
+    space.unmap(memory::mapper(), frame_alloc::frame_allocator(), start, size).unwrap();
+    space.reserve(start, size, Usage::Stack).unwrap();
+}
+
+/// Continue on the stack with the given top by calling `entry`, never to return to the current one.
+pub unsafe fn switch_to(top: VirtAddr, entry: extern "C" fn() -> !) -> ! {
+    asm!("mov $0, %rsp
+          xor %rbp, %rbp
+          call *$1
+          ud2"
+        :: "r" (top.as_u64() & !0xF), "r" (entry as u64)
+        : "memory"
+        : "volatile");
+    unreachable!()
+}
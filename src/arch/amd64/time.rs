@@ -0,0 +1,305 @@
+//! Timekeeping: a monotonic clock driven by the TSC and a LAPIC timer, both calibrated against the
+//! HPET or, lacking one, the PIT.
+//!
+//! Waiting works before `init` too, straight on the PIT, so that the panic handler can use it at
+//! any point.
+use core::arch::x86_64::_rdtsc;
+use core::ptr;
+use core::time::Duration;
+
+use x86_64::VirtAddr;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+use x86::cpuid::CpuId;
+
+use log::{info, warn};
+
+use super::acpi;
+use super::apic::{self, LAPIC_LVT_TIMER, LAPIC_TIMER_INITIAL_COUNT, LAPIC_TIMER_CURRENT_COUNT, LAPIC_TIMER_DIVIDE, LVT_MASKED};
+use super::interrupts::{self, InterruptHandler};
+use super::mmio::{self, CacheMode};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+/// How long the TSC and LAPIC timer are measured for.
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+/// Period of the tick `init` starts, which wakes up `sleep`.
+pub const TICK_PERIOD: Duration = Duration::from_millis(1);
+
+/// Offsets within the HPET table and the HPET's registers.
+const HPET_TABLE_ADDRESS: usize = 44;
+const HPET_REGISTERS_SIZE: u64 = 0x400;
+const HPET_CAPABILITIES: u64 = 0x00;
+const HPET_CONFIGURATION: u64 = 0x10;
+const HPET_MAIN_COUNTER: u64 = 0xF0;
+const HPET_COUNTER_64BIT: u64 = 1 << 13;
+const HPET_ENABLE: u64 = 1;
+/// Longest counter period the HPET specification allows, 100 ns.
+const HPET_MAX_PERIOD_FS: u64 = 0x05F5_E100;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Port B of the keyboard controller, which gates channel 2 and shows its output.
+const PIT_GATE: u16 = 0x61;
+const PIT_GATE_ENABLE: u8 = 1 << 0;
+const PIT_SPEAKER_ENABLE: u8 = 1 << 1;
+const PIT_OUTPUT: u8 = 1 << 5;
+/// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary.
+const PIT_ONE_SHOT: u8 = 0b1011_0000;
+
+/// LAPIC timer divider configuration for dividing its clock by 16.
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// What the TSC and LAPIC timer are calibrated against.
+#[derive(Debug)]
+enum ReferenceClock {
+    Hpet { registers: VirtAddr, period_fs: u64, counter_mask: u64 },
+    Pit,
+}
+
+impl ReferenceClock {
+    /// The HPET, enabled, if the firmware tells us about one.
+    unsafe fn find() -> Self {
+        let table = match acpi::find_table(b"HPET") {
+            Some(table) => table,
+            None => return ReferenceClock::Pit,
+        };
+        let phys = x86_64::PhysAddr::new(table.read_u64(HPET_TABLE_ADDRESS));
+        let registers = match mmio::ioremap(phys, HPET_REGISTERS_SIZE, CacheMode::Uncacheable) {
+            Ok(registers) => registers,
+            Err(err) => {
+                warn!("Failed to map the HPET at {:?}: {:?}", phys, err);
+                return ReferenceClock::Pit;
+            }
+        };
+
+        let capabilities = hpet_read(registers, HPET_CAPABILITIES);
+        let period_fs = capabilities >> 32;
+        if period_fs == 0 || period_fs > HPET_MAX_PERIOD_FS {
+            warn!("HPET at {:?} reports a period of {} fs, not using it.", phys, period_fs);
+            mmio::iounmap(registers);
+            return ReferenceClock::Pit;
+        }
+        let counter_mask = if capabilities & HPET_COUNTER_64BIT != 0 { u64::max_value() } else { u32::max_value() as u64 };
+        hpet_write(registers, HPET_CONFIGURATION, hpet_read(registers, HPET_CONFIGURATION) | HPET_ENABLE);
+        info!("HPET at {:?} runs at {} Hz.", phys, NANOS_PER_SEC * FEMTOS_PER_NANO / period_fs);
+        ReferenceClock::Hpet { registers, period_fs, counter_mask }
+    }
+
+    /// Wait for at least `duration`, returning how long it actually took in nanoseconds.
+    fn wait(&self, duration: Duration) -> u64 {
+        match *self {
+            ReferenceClock::Hpet { registers, period_fs, counter_mask } => {
+                let ticks = duration.as_nanos() as u64 * FEMTOS_PER_NANO / period_fs;
+                let start = hpet_read(registers, HPET_MAIN_COUNTER);
+                let elapsed = loop {
+                    let elapsed = hpet_read(registers, HPET_MAIN_COUNTER).wrapping_sub(start) & counter_mask;
+                    if elapsed >= ticks { break elapsed; }
+                    core::sync::atomic::spin_loop_hint();
+                };
+                (elapsed as u128 * period_fs as u128 / FEMTOS_PER_NANO as u128) as u64
+            }
+            ReferenceClock::Pit => {
+                pit_wait(duration);
+                duration.as_nanos() as u64
+            }
+        }
+    }
+}
+
+fn hpet_read(registers: VirtAddr, reg: u64) -> u64 {
+    unsafe { ptr::read_volatile((registers + reg).as_ptr::<u64>()) }
+}
+
+fn hpet_write(registers: VirtAddr, reg: u64, value: u64) {
+    unsafe { ptr::write_volatile((registers + reg).as_mut_ptr::<u64>(), value) }
+}
+
+/// Spin on PIT channel 2, as many one-shots as it takes as it counts 16 bits at most.
+fn pit_wait(duration: Duration) {
+    let mut remaining = (duration.as_nanos() * PIT_FREQUENCY as u128 / NANOS_PER_SEC as u128) as u64;
+    let (mut gate, mut command, mut channel2) = (Port::<u8>::new(PIT_GATE), Port::<u8>::new(PIT_COMMAND), Port::<u8>::new(PIT_CHANNEL2));
+    while remaining > 0 {
+        let count = core::cmp::min(remaining, 0xFFFF);
+        unsafe {
+            // Counting starts once the count is written, while the gate is up.
+            let value = gate.read() & !(PIT_GATE_ENABLE | PIT_SPEAKER_ENABLE);
+            gate.write(value);
+            command.write(PIT_ONE_SHOT);
+            channel2.write(count as u8);
+            channel2.write((count >> 8) as u8);
+            gate.write(value | PIT_GATE_ENABLE);
+            while gate.read() & PIT_OUTPUT == 0 {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+        remaining -= count;
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// LAPIC timer modes, with the time between interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    Periodic(Duration),
+    OneShot(Duration),
+}
+
+#[derive(Debug)]
+struct Clock {
+    tsc_hz: u64,
+    /// TSC value at which the monotonic clock reads zero.
+    tsc_base: u64,
+    /// Frequency the LAPIC timer counts down at, after its divider.
+    lapic_timer_hz: u64,
+    timer_vector: u8,
+    timer_mode: Option<TimerMode>,
+    timer_handler: Option<InterruptHandler>,
+}
+
+static mut CLOCK: Option<Clock> = None;
+
+fn clock() -> Option<&'static mut Clock> {
+    unsafe { CLOCK.as_mut() }
+}
+
+/// Calibrate the TSC and the LAPIC timer and start ticking every `TICK_PERIOD`.
+///
+/// To be called once the APICs are set up, and before the ACPI tables are reclaimed.
+pub unsafe fn init() {
+    let cpuid = CpuId::new();
+    assert!(cpuid.get_feature_info().map(|it| it.has_tsc()).unwrap_or(false), "CPU has no TSC.");
+    if !cpuid.get_extended_function_info().map(|it| it.has_invariant_tsc()).unwrap_or(false) {
+        warn!("TSC is not invariant, the clock may drift with frequency changes.");
+    }
+
+    let reference = ReferenceClock::find();
+    let lapic = apic::local_apic();
+    lapic.write(LAPIC_LVT_TIMER, LVT_MASKED);
+    lapic.write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+
+    // Measure both over the same period.
+    lapic.write(LAPIC_TIMER_INITIAL_COUNT, u32::max_value());
+    let tsc_start = rdtsc();
+    let elapsed_ns = reference.wait(CALIBRATION_TIME);
+    let tsc_end = rdtsc();
+    let lapic_ticks = u32::max_value() - lapic.read(LAPIC_TIMER_CURRENT_COUNT);
+    lapic.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+
+    let tsc_hz = ((tsc_end - tsc_start) as u128 * NANOS_PER_SEC as u128 / elapsed_ns as u128) as u64;
+    let lapic_timer_hz = (lapic_ticks as u128 * NANOS_PER_SEC as u128 / elapsed_ns as u128) as u64;
+    let timer_vector = interrupts::allocate_vector(timer_interrupt).expect("No vector left for the LAPIC timer.");
+    info!("Calibrated against {}: TSC at {} MHz, LAPIC timer at {} kHz on vector 0x{:X}.",
+        match reference { ReferenceClock::Hpet { .. } => "the HPET", ReferenceClock::Pit => "the PIT" },
+        tsc_hz / 1_000_000, lapic_timer_hz / 1000, timer_vector);
+
+    CLOCK = Some(Clock {
+        tsc_hz, tsc_base: tsc_start, lapic_timer_hz, timer_vector,
+        timer_mode: None, timer_handler: None,
+    });
+    start_timer(TimerMode::Periodic(TICK_PERIOD));
+}
+
+/// Nanoseconds since `init`, or zero before it.
+pub fn now_ns() -> u64 {
+    match clock() {
+        Some(clock) => ns_since(clock.tsc_base),
+        None => 0,
+    }
+}
+
+/// Raw TSC value, for `ns_since`.
+pub fn read_tsc() -> u64 {
+    rdtsc()
+}
+
+/// Nanoseconds since the TSC read `tsc`, which may be from before `init`. Zero before `init`, as
+/// the TSC's rate is unknown until then.
+pub fn ns_since(tsc: u64) -> u64 {
+    match clock() {
+        Some(clock) => (rdtsc().saturating_sub(tsc) as u128 * NANOS_PER_SEC as u128 / clock.tsc_hz as u128) as u64,
+        None => 0,
+    }
+}
+
+/// Time since `init`.
+pub fn now() -> Duration {
+    Duration::from_nanos(now_ns())
+}
+
+/// Spin for `duration`, without relying on interrupts.
+pub fn busy_wait(duration: Duration) {
+    if clock().is_none() {
+        pit_wait(duration);
+        return;
+    }
+    let deadline = now_ns() + duration.as_nanos() as u64;
+    while now_ns() < deadline {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Wait for `duration`, halting in between timer ticks when interrupts are enabled.
+pub fn sleep(duration: Duration) {
+    let is_ticking = clock().map(|it| match it.timer_mode { Some(TimerMode::Periodic(_)) => true, _ => false }).unwrap_or(false);
+    if !is_ticking || !cpu_interrupts::are_enabled() {
+        busy_wait(duration);
+        return;
+    }
+    let deadline = now_ns() + duration.as_nanos() as u64;
+    while now_ns() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// (Re)program the LAPIC timer.
+pub fn start_timer(mode: TimerMode) {
+    // The timer interrupt clears the mode of a one-shot, it must not fire halfway through.
+    cpu_interrupts::without_interrupts(|| {
+        let clock = clock().expect("Timekeeping is not set up yet.");
+        let (duration, periodic) = match mode {
+            TimerMode::Periodic(duration) => (duration, LVT_TIMER_PERIODIC),
+            TimerMode::OneShot(duration) => (duration, 0),
+        };
+        let count = duration.as_nanos() * clock.lapic_timer_hz as u128 / NANOS_PER_SEC as u128;
+        let count = core::cmp::max(1, core::cmp::min(count, u32::max_value() as u128)) as u32;
+
+        let lapic = apic::local_apic();
+        lapic.write(LAPIC_LVT_TIMER, clock.timer_vector as u32 | periodic);
+        lapic.write(LAPIC_TIMER_INITIAL_COUNT, count);
+        clock.timer_mode = Some(mode);
+    });
+}
+
+pub fn stop_timer() {
+    cpu_interrupts::without_interrupts(|| {
+        let clock = clock().expect("Timekeeping is not set up yet.");
+        let lapic = apic::local_apic();
+        lapic.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+        clock.timer_mode = None;
+    });
+}
+
+/// Have `handler` called on every LAPIC timer interrupt, replacing any previous one.
+pub fn set_timer_handler(handler: Option<InterruptHandler>) {
+    clock().expect("Timekeeping is not set up yet.").timer_handler = handler;
+}
+
+fn timer_interrupt() {
+    if let Some(clock) = clock() {
+        if let Some(TimerMode::OneShot(_)) = clock.timer_mode {
+            clock.timer_mode = None;
+        }
+        if let Some(handler) = clock.timer_handler {
+            handler();
+        }
+    }
+}
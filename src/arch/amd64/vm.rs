@@ -0,0 +1,381 @@
+//! Bookkeeping of virtual address spaces.
+//!
+//! An `AddressSpace` keeps track of which parts of a range of virtual memory are in use, what
+//! for, with which permissions and what backs them, and makes sure the page tables agree. The
+//! kernel's own address space covers the higher half, see `kernel_space`. Areas are stored in a
+//! fixed size array rather than on the heap, as the kernel's address space is set up before there
+//! is a heap and survives our move to the higher half.
+use x86_64::{PhysAddr, VirtAddr};
+
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::page::{PageSize as PageSizeT, Size4KiB, Page};
+use x86_64::structures::paging::mapper::{Mapper, OffsetPageTable, MapToError};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, UnusedPhysFrame};
+
+use log::info;
+
+use byte_unit::*;
+
+use heapless::consts::U256 as HLU256;
+
+use super::frame_alloc::BitmapFrameAllocator;
+use super::memory::{self, PageSize, MappingStats};
+
+static mut KERNEL_SPACE: Option<AddressSpace> = None;
+
+/// What backs the pages of an area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Nothing through this address space, the area is only reserved. Whoever reserved it may
+    /// still map pages in it themselves, like the kernel heap does.
+    Reserved,
+    /// A fixed range of physical memory, starting at the given address.
+    Physical(PhysAddr),
+    /// Frames from the frame allocator, which are returned to it when unmapped.
+    Anonymous,
+}
+
+/// What an area is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    /// Memory the firmware loaded us into, our image included.
+    Loader,
+    /// The mapping of all physical memory.
+    PhysicalMemory,
+    Heap,
+    Stack,
+    /// An unmapped page below a stack, so that overflowing it faults instead of silently
+    /// corrupting whatever lies below.
+    Guard,
+    Mmio,
+    Other,
+}
+
+impl Usage {
+    /// Largest pages areas with this usage are mapped with. Areas whose permissions are changed
+    /// page by page, such as our image, must stick to 4 KiB pages.
+    fn max_page_size(&self) -> PageSize {
+        match self {
+            Usage::PhysicalMemory | Usage::Mmio => PageSize::Huge(),
+            _ => PageSize::Normal(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// The range is not page aligned or empty.
+    InvalidRange,
+    /// The range lies outside of the address space.
+    OutOfBounds,
+    /// The range overlaps an area it may not overlap.
+    Overlap,
+    /// Part of the range is not covered by any area.
+    NotMapped,
+    /// No free range of the requested size is left.
+    OutOfAddressSpace,
+    /// The address space can't keep track of any more areas.
+    TooManyAreas,
+    /// The range would split a 2 MiB or 1 GiB page.
+    SplitsLargePage,
+    /// No physical memory is left to back the range or its page tables.
+    OutOfMemory,
+}
+
+/// A virtual memory area, a page aligned range of an address space used for a single purpose.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    /// End of the area, exclusive.
+    pub end: VirtAddr,
+    /// Flags its pages are mapped with, empty for reserved areas.
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    pub usage: Usage,
+}
+
+impl Vma {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Split the area at `at`, keeping the lower part and returning the upper part.
+    fn split_off(&mut self, at: VirtAddr) -> Vma {
+        let mut upper = *self;
+        upper.start = at;
+        if let Backing::Physical(phys) = self.backing {
+            upper.backing = Backing::Physical(phys + (at - self.start));
+        }
+        self.end = at;
+        upper
+    }
+}
+
+/// A range of virtual memory divided into areas, see the module documentation.
+pub struct AddressSpace {
+    start: VirtAddr,
+    end: VirtAddr,
+    /// Areas sorted by start address, never overlapping.
+    areas: heapless::Vec<Vma, HLU256>,
+}
+
+impl AddressSpace {
+    /// An empty address space managing `start` up to `end`.
+    pub fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        assert!(start.is_aligned(Size4KiB::SIZE) && end.is_aligned(Size4KiB::SIZE) && start < end);
+        Self { start, end, areas: heapless::Vec::new() }
+    }
+
+    /// The area containing `addr`, if any.
+    pub fn query(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas.iter().find(|it| it.contains(addr))
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter()
+    }
+
+    /// Dump all areas over the kernel log.
+    pub fn log(&self) {
+        info!("Address space 0x{:X}-0x{:X}:", self.start.as_u64(), self.end.as_u64());
+        for area in self.areas.iter() {
+            info!("\t0x{:016X}-0x{:016X} {:>10} {:?} {:?} {:?}", area.start.as_u64(), area.end.as_u64(),
+                Byte::from_bytes(area.size() as u128).get_appropriate_unit(true).to_string(),
+                area.usage, area.backing, area.flags);
+        }
+    }
+
+    /// Reserve `size` bytes at `start`, which must not overlap any other area.
+    pub fn reserve(&mut self, start: VirtAddr, size: u64, usage: Usage) -> Result<(), VmError> {
+        let end = self.check_range(start, size)?;
+        if self.areas.iter().any(|it| it.start < end && it.end > start) {
+            return Err(VmError::Overlap);
+        }
+        self.insert(Vma { start, end, flags: PageTableFlags::empty(), backing: Backing::Reserved, usage })
+    }
+
+    /// Reserve `size` bytes anywhere in the address space, aligned to `align`.
+    pub fn reserve_anywhere(&mut self, size: u64, align: u64, usage: Usage) -> Result<VirtAddr, VmError> {
+        let start = self.find_free(size, align).ok_or(VmError::OutOfAddressSpace)?;
+        self.reserve(start, size, usage)?;
+        Ok(start)
+    }
+
+    /// Lowest free range of `size` bytes aligned to `align`.
+    pub fn find_free(&self, size: u64, align: u64) -> Option<VirtAddr> {
+        let align = core::cmp::max(align, Size4KiB::SIZE);
+        let mut candidate = self.start.align_up(align);
+        for area in self.areas.iter() {
+            if area.start >= candidate && area.start - candidate >= size {
+                break;
+            }
+            if area.end > candidate {
+                candidate = area.end.align_up(align);
+            }
+        }
+        if candidate < self.end && self.end - candidate >= size { Some(candidate) } else { None }
+    }
+
+    /// Lowest range of `size` bytes aligned to `align` that lies within a single area reserved for
+    /// `usage`, to be mapped by whoever reserved the area.
+    pub fn find_reserved(&self, size: u64, align: u64, usage: Usage) -> Option<VirtAddr> {
+        let align = core::cmp::max(align, Size4KiB::SIZE);
+        self.areas.iter()
+            .filter(|it| it.backing == Backing::Reserved && it.usage == usage)
+            .map(|it| (it.start.align_up(align), it.end))
+            .find(|(start, end)| start < end && *end - *start >= size)
+            .map(|(start, _)| start)
+    }
+
+    /// Map `size` bytes at `start` with the given flags and backing, returning the pages it took.
+    ///
+    /// The range must either be free or lie entirely within areas that are only reserved, which
+    /// are then taken over by the new area.
+    pub unsafe fn map(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        allocator: &mut BitmapFrameAllocator,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+        usage: Usage) -> Result<MappingStats, VmError> {
+        let end = self.check_range(start, size)?;
+
+        let overlapping = self.areas.iter().filter(|it| it.start < end && it.end > start);
+        if overlapping.clone().any(|it| it.backing != Backing::Reserved) {
+            return Err(VmError::Overlap);
+        }
+        if overlapping.clone().next().is_some() && !self.is_covered(start, end) {
+            return Err(VmError::Overlap);
+        }
+
+        // Splitting reserved areas at both ends and recording the new one takes up to three more
+        // slots, make sure they are there before touching the page tables.
+        if self.areas.len() + 3 > self.areas.capacity() { return Err(VmError::TooManyAreas); }
+
+        let stats = match backing {
+            Backing::Reserved => MappingStats::default(),
+            Backing::Physical(phys) => memory::map_range(mapper, start, phys, size, flags, usage.max_page_size(), allocator)
+                .map_err(|err| match err {
+                    MapToError::FrameAllocationFailed => VmError::OutOfMemory,
+                    // Mapped behind our back, or part of a large page we don't know about.
+                    MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => VmError::Overlap,
+                })?,
+            Backing::Anonymous => map_anonymous(mapper, allocator, start, end, flags)?,
+        };
+        self.carve(start, end)?;
+        self.remove(start, end);
+        self.insert(Vma { start, end, flags, backing, usage })?;
+        Ok(stats)
+    }
+
+    /// Unmap `size` bytes at `start`, returning anonymous memory to the frame allocator. Every part of the range must be covered by an area, reserved areas are simply
+    /// forgotten.
+    pub unsafe fn unmap(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        allocator: &mut BitmapFrameAllocator,
+        start: VirtAddr,
+        size: u64) -> Result<(), VmError> {
+        let end = self.check_range(start, size)?;
+        if !self.is_covered(start, end) { return Err(VmError::NotMapped); }
+        check_large_pages(mapper, start, end)?;
+
+        self.carve(start, end)?;
+        for area in self.areas.iter().filter(|it| it.start >= start && it.end <= end) {
+            if area.backing != Backing::Reserved {
+                memory::unmap_range(mapper, area.start, area.size(), area.backing == Backing::Anonymous, allocator);
+            }
+        }
+        self.remove(start, end);
+        Ok(())
+    }
+
+    /// Change the flags of `size` bytes at `start`, which must be covered by mapped areas.
+    pub unsafe fn protect(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags) -> Result<(), VmError> {
+        let end = self.check_range(start, size)?;
+        if !self.is_covered(start, end) { return Err(VmError::NotMapped); }
+        if self.areas.iter().any(|it| it.start < end && it.end > start && it.backing == Backing::Reserved) {
+            return Err(VmError::NotMapped);
+        }
+        check_large_pages(mapper, start, end)?;
+
+        self.carve(start, end)?;
+        memory::protect_range(mapper, start, size, flags);
+        for area in self.areas.iter_mut().filter(|it| it.start >= start && it.end <= end) {
+            area.flags = flags;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, start: VirtAddr, size: u64) -> Result<VirtAddr, VmError> {
+        if size == 0 || !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+            return Err(VmError::InvalidRange);
+        }
+        if start < self.start || start > self.end || self.end - start < size {
+            return Err(VmError::OutOfBounds);
+        }
+        Ok(start + size)
+    }
+
+    /// Whether every part of the range is covered by an area.
+    fn is_covered(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        let mut cursor = start;
+        for area in self.areas.iter().filter(|it| it.start < end && it.end > start) {
+            if area.start > cursor { return false; }
+            cursor = area.end;
+        }
+        cursor >= end
+    }
+
+    /// Split areas so that no area crosses `start` or `end`.
+    fn carve(&mut self, start: VirtAddr, end: VirtAddr) -> Result<(), VmError> {
+        for at in [start, end].iter().cloned() {
+            if let Some(i) = self.areas.iter().position(|it| it.start < at && it.end > at) {
+                if self.areas.len() == self.areas.capacity() { return Err(VmError::TooManyAreas); }
+                let upper = self.areas[i].split_off(at);
+                self.insert(upper)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget all areas lying entirely within the range.
+    fn remove(&mut self, start: VirtAddr, end: VirtAddr) {
+        let mut i = 0;
+        while i < self.areas.len() {
+            if self.areas[i].start >= start && self.areas[i].end <= end {
+                self.areas.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        self.areas.sort_unstable_by_key(|it| it.start);
+    }
+
+    fn insert(&mut self, area: Vma) -> Result<(), VmError> {
+        self.areas.push(area).map_err(|_| VmError::TooManyAreas)?;
+        self.areas.sort_unstable_by_key(|it| it.start);
+        Ok(())
+    }
+}
+
+/// Back the range with fresh frames, undoing everything if that fails halfway.
+unsafe fn map_anonymous(
+    mapper: &mut OffsetPageTable,
+    allocator: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags) -> Result<MappingStats, VmError> {
+    let first: Page<Size4KiB> = Page::from_start_address(start).unwrap();
+    let count = (end - start) / Size4KiB::SIZE;
+
+    for i in 0..count {
+        let mapped = match FrameAllocator::<Size4KiB>::allocate_frame(allocator) {
+            Some(frame) => {
+                let phys = *frame;
+                let result = mapper.map_to(first + i, frame, flags, allocator);
+                if result.is_err() {
+                    // The frame never made it into the page tables, see `memory::grow_kernel_heap`.
+                    FrameDeallocator::<Size4KiB>::deallocate_frame(allocator, UnusedPhysFrame::new(phys));
+                }
+                result.map(|flush| flush.flush()).is_ok()
+            }
+            None => false,
+        };
+        if !mapped {
+            memory::unmap_range(mapper, start, i * Size4KiB::SIZE, true, allocator);
+            return Err(VmError::OutOfMemory);
+        }
+    }
+    Ok(MappingStats { normal: count, ..MappingStats::default() })
+}
+
+/// Make sure the range does not start or end in the middle of a large page.
+fn check_large_pages(mapper: &OffsetPageTable, start: VirtAddr, end: VirtAddr) -> Result<(), VmError> {
+    for addr in [start, end].iter().cloned() {
+        if let Some((_, page_size)) = memory::translate(mapper, addr) {
+            if !addr.is_aligned(page_size.size()) { return Err(VmError::SplitsLargePage); }
+        }
+    }
+    Ok(())
+}
+
+/// Set up the kernel's address space, covering the higher half.
+pub fn init_kernel_space(start: VirtAddr, end: VirtAddr) {
+    unsafe { KERNEL_SPACE = Some(AddressSpace::new(start, end)); }
+}
+
+pub fn kernel_space() -> &'static mut AddressSpace {
+    unsafe { KERNEL_SPACE.as_mut().expect("Kernel address space is not set up yet.") }
+}
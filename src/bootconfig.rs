@@ -0,0 +1,173 @@
+//! Boot configuration, read from the EFI system partition before exiting boot services.
+//!
+//! The configuration file is a list of `key = value` lines, empty lines and lines starting with
+//! `#` are ignored. Unknown keys and invalid or out of range values are reported and otherwise
+//! ignored, so a broken configuration file never prevents booting. For example:
+//!
+//! ```text
+//! emergency_heap_size = 2 MiB
+//! max_memory_descriptors = 256
+//! log_level = debug
+//! heap_backend = kernel
+//! ```
+use core::str;
+
+use log::{debug, info, warn, error, LevelFilter};
+
+use uefi::prelude::*;
+use uefi::table::boot::BootServices;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::proto::media::file::{File, FileMode, FileAttribute, FileType};
+
+use byte_unit::*;
+
+use crate::kernalloc::HeapBackend;
+
+/// Location of the configuration file, relative to the root of the volume we were loaded from.
+const CONFIG_PATH: &str = "\\ninos.cfg";
+const MAX_CONFIG_SIZE: usize = 4096;
+
+/// Ranges values are accepted in. The emergency heap has to hold kernalloc's bookkeeping, and the
+/// memory map needs some room to describe anything usable at all.
+const MIN_EMERGENCY_HEAP_SIZE: usize = 64 * 1024;
+const MAX_EMERGENCY_HEAP_SIZE: usize = 256 * 1024 * 1024;
+const MIN_MEMORY_DESCRIPTORS: usize = 32;
+const MAX_MEMORY_DESCRIPTORS: usize = 512;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootConfig {
+    /// Size of the emergency heap kernalloc falls back on after exiting boot services, from 64 KiB
+    /// up to 256 MiB.
+    pub emergency_heap_size: usize,
+    /// Amount of UEFI memory descriptors kept in the memory map, from 32 up to the 512 it has room
+    /// for.
+    pub max_memory_descriptors: usize,
+    pub log_level: LevelFilter,
+    /// Heap used once paging is up.
+    pub heap_backend: HeapBackend,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            emergency_heap_size: n_mib_bytes!(1) as usize,
+            max_memory_descriptors: 200,
+            log_level: LevelFilter::Info,
+            heap_backend: HeapBackend::Kernel,
+        }
+    }
+}
+
+impl BootConfig {
+    /// Parse a configuration file, anything not mentioned in it keeps its default value.
+    ///
+    /// This runs before kernalloc is initialized, so it must not allocate.
+    pub fn parse(text: &str) -> Self {
+        let mut config = Self::default();
+
+        for (number, line) in text.lines().enumerate().map(|(i, it)| (i + 1, it.trim())) {
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(v) => v.trim(),
+                None => { warn!("Boot config line {}: expected 'key = value'", number); continue; }
+            };
+
+            let valid = match key {
+                "emergency_heap_size" => parse_size(number, value)
+                    .filter(|it| in_range(number, key, *it, MIN_EMERGENCY_HEAP_SIZE, MAX_EMERGENCY_HEAP_SIZE))
+                    .map(|it| config.emergency_heap_size = it).is_some(),
+                "max_memory_descriptors" => value.parse().ok()
+                    .filter(|it| in_range(number, key, *it, MIN_MEMORY_DESCRIPTORS, MAX_MEMORY_DESCRIPTORS))
+                    .map(|it| config.max_memory_descriptors = it).is_some(),
+                "log_level" => value.parse().ok()
+                    .map(|it| config.log_level = it).is_some(),
+                "heap_backend" => match value {
+                    "kernel" => { config.heap_backend = HeapBackend::Kernel; true }
+                    "emergency" => { config.heap_backend = HeapBackend::Emergency; true }
+                    _ => false,
+                },
+                _ => { warn!("Boot config line {}: unknown key '{}'", number, key); continue; }
+            };
+
+            if !valid {
+                warn!("Boot config line {}: invalid value '{}' for {}, ignoring it", number, value, key);
+            }
+        }
+        config
+    }
+
+    /// Read the configuration file from the volume the given image was loaded from, falling back
+    /// to the defaults if there is none.
+    pub fn load(bs: &BootServices, image: Handle) -> Self {
+        let mut buf = [0u8; MAX_CONFIG_SIZE];
+        let config = match read_config_file(bs, image, &mut buf) {
+            Ok(text) => {
+                info!("Loaded boot config from {}", CONFIG_PATH);
+                Self::parse(text)
+            }
+            Err(status) => {
+                info!("No usable boot config at {} ({:?}), using defaults.", CONFIG_PATH, status);
+                Self::default()
+            }
+        };
+        debug!("{:?}", config);
+        config
+    }
+}
+
+/// Size suffixes accepted by `parse_size`, all taken as powers of 1024.
+const SIZE_SUFFIXES: [(&str, u32); 8] = [
+    ("", 0), ("B", 0),
+    ("K", 10), ("KiB", 10),
+    ("M", 20), ("MiB", 20),
+    ("G", 30), ("GiB", 30),
+];
+
+/// Parse a number of bytes followed by one of `SIZE_SUFFIXES`, warning about any other suffix.
+/// Unlike `Byte::from_str`, this doesn't allocate on invalid input.
+fn parse_size(number: usize, value: &str) -> Option<usize> {
+    let digits = value.find(|it: char| !it.is_ascii_digit()).unwrap_or(value.len());
+    let size: usize = value[..digits].parse().ok()?;
+    let suffix = value[digits..].trim_start();
+
+    match SIZE_SUFFIXES.iter().find(|(it, _)| *it == suffix) {
+        Some((_, shift)) => size.checked_mul(1 << shift),
+        None => {
+            warn!("Boot config line {}: unknown size suffix '{}', expected one of K, KiB, M, MiB, G or GiB", number, suffix);
+            None
+        }
+    }
+}
+
+/// Whether `value` lies within `min..=max`, warning about it if not.
+fn in_range(number: usize, key: &str, value: usize, min: usize, max: usize) -> bool {
+    let valid = value >= min && value <= max;
+    if !valid {
+        warn!("Boot config line {}: {} must lie between {} and {}", number, key, min, max);
+    }
+    valid
+}
+
+fn read_config_file<'a>(bs: &BootServices, image: Handle, buf: &'a mut [u8]) -> Result<&'a str, Status> {
+    let loaded_image = bs.handle_protocol::<LoadedImage>(image).warning_as_error().map_err(|e| e.status())?;
+    let device = unsafe { &*loaded_image.get() }.device();
+
+    let fs = bs.handle_protocol::<SimpleFileSystem>(device).warning_as_error().map_err(|e| e.status())?;
+    let mut root = unsafe { &mut *fs.get() }.open_volume().warning_as_error().map_err(|e| e.status())?;
+
+    let handle = root.open(CONFIG_PATH, FileMode::Read, FileAttribute::empty()).warning_as_error().map_err(|e| e.status())?;
+    let mut file = match handle.into_type().warning_as_error().map_err(|e| e.status())? {
+        FileType::Regular(file) => file,
+        FileType::Dir(_) => return Err(Status::INVALID_PARAMETER),
+    };
+
+    let len = file.read(buf).warning_as_error().map_err(|e| e.status())?;
+    if len == buf.len() {
+        warn!("Boot config is larger than {} bytes, the rest is ignored.", MAX_CONFIG_SIZE);
+    }
+    str::from_utf8(&buf[..len]).map_err(|_| Status::INVALID_PARAMETER)
+}
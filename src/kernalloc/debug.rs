@@ -0,0 +1,119 @@
+//! Heap corruption detection, enabled with the `heap-debug` feature.
+//!
+//! Every allocation is surrounded by red zones filled with `REDZONE_BYTE`, fresh allocations are
+//! filled with `ALLOC_POISON` and freed ones with `FREE_POISON`, as is all free memory in the free
+//! lists. The red zones are checked when a block is freed, the poison when free memory is handed
+//! out again, and both by `heap_check`, which walks all live allocations and free blocks.
+
+use core::slice;
+
+use log::{debug, info, warn, error};
+
+use super::header::{BlockHeader, BlockType, REDZONE};
+use super::free_list::HOLE_HEADER_SIZE;
+use super::leak;
+
+pub const REDZONE_BYTE: u8 = 0xFD;
+pub const ALLOC_POISON: u8 = 0xCD;
+pub const FREE_POISON: u8 = 0xDD;
+
+/// Fill the red zones and poison the user data of a fresh allocation.
+pub unsafe fn arm(ptr: *mut u8, size: usize) {
+    ptr.sub(REDZONE).write_bytes(REDZONE_BYTE, REDZONE);
+    ptr.write_bytes(ALLOC_POISON, size);
+    ptr.add(size).write_bytes(REDZONE_BYTE, REDZONE);
+}
+
+/// Poison the user data of a block that is about to be freed.
+pub unsafe fn poison(ptr: *mut u8, size: usize) {
+    ptr.write_bytes(FREE_POISON, size);
+}
+
+fn dump(what: &str, bytes: &[u8]) {
+    error!("\t{}: {:02X?}", what, bytes);
+}
+
+/// Verify the header and both red zones of a live allocation, reporting any damage over the
+/// kernel log. Returns whether the block is intact.
+pub unsafe fn check(ptr: *mut u8) -> bool {
+    let header = BlockHeader::of(ptr);
+    if !header.is_live() {
+        error!("Heap corruption: header of {:?} is damaged or the block was freed", ptr);
+        dump("header", slice::from_raw_parts(header as *const BlockHeader as *const u8, core::mem::size_of::<BlockHeader>()));
+        return false;
+    }
+
+    let front = slice::from_raw_parts(ptr.sub(REDZONE), REDZONE);
+    let back = slice::from_raw_parts(ptr.add(header.size), REDZONE);
+    let mut intact = true;
+
+    for (name, zone) in [("front", front), ("back", back)].iter() {
+        if let Some(offset) = zone.iter().position(|it| *it != REDZONE_BYTE) {
+            error!("Heap corruption: {} red zone of {:?} ({} bytes, align {}, owned by {}) overwritten at byte {}",
+                name, ptr, header.size, header.align, BlockType::name(header.owner), offset);
+            dump(name, zone);
+            intact = false;
+        }
+    }
+    intact
+}
+
+/// Verify that memory taken from a free list is still entirely poisoned, reporting any damage
+/// over the kernel log. Anything else means it was written to after being freed.
+pub unsafe fn check_free(block: *mut u8, size: usize) -> bool {
+    let bytes = slice::from_raw_parts(block, size);
+    match bytes.iter().position(|it| *it != FREE_POISON) {
+        None => true,
+        Some(offset) => {
+            error!("Heap corruption: free memory at {:?} written to at byte {}, use after free?", block, offset);
+            dump("free memory", &bytes[offset..core::cmp::min(offset + 32, size)]);
+            false
+        }
+    }
+}
+
+/// Check the free blocks of one allocator: they must be in address order without touching, and
+/// poisoned apart from their headers. Returns the amount of blocks and how many are damaged.
+fn check_holes(name: &str, for_each_hole: impl FnOnce(&mut dyn FnMut(usize, usize))) -> (usize, usize) {
+    let mut checked = 0;
+    let mut damaged = 0;
+    let mut previous_end = 0;
+    for_each_hole(&mut |addr, size| {
+        checked += 1;
+        if addr <= previous_end && previous_end != 0 {
+            error!("Heap corruption: free block 0x{:X} of {} bytes in {} is out of order", addr, size, name);
+            damaged += 1;
+        } else if !unsafe { check_free((addr + HOLE_HEADER_SIZE) as *mut u8, size.saturating_sub(HOLE_HEADER_SIZE)) } {
+            damaged += 1;
+        }
+        previous_end = addr + size;
+    });
+    (checked, damaged)
+}
+
+/// Walk all live allocations and the free lists of all allocators and check them for corruption,
+/// returning the amount of damaged blocks.
+pub fn heap_check() -> usize {
+    let mut checked = 0;
+    let mut damaged = 0;
+    leak::for_each_live(|ptr, _size| {
+        checked += 1;
+        if !unsafe { check(ptr) } { damaged += 1; }
+    });
+
+    let mut free = (0, 0);
+    if let Some(heap) = unsafe { super::statalloc.as_ref() } {
+        free = check_holes(BlockType::name(BlockType::STAT), |f| heap.for_each_hole(f));
+    }
+    if let Some(heap) = unsafe { super::kernelheap.as_ref() } {
+        let (holes, damaged_holes) = check_holes(BlockType::name(BlockType::HEAP), |f| heap.for_each_hole(f));
+        free = (free.0 + holes, free.1 + damaged_holes);
+    }
+
+    if damaged == 0 && free.1 == 0 {
+        info!("Heap check: {} live blocks and {} free blocks, all intact.", checked, free.0);
+    } else {
+        error!("Heap check: {} of {} live blocks and {} of {} free blocks are damaged!", damaged, checked, free.1, free.0);
+    }
+    damaged + free.1
+}
@@ -0,0 +1,181 @@
+use core::mem;
+use core::ptr;
+
+#[cfg(feature = "heap-debug")]
+use super::debug::FREE_POISON;
+
+/// Header of a free block, stored in the first bytes of the free memory itself.
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+/// Smallest block the free list can keep track of.
+pub const MIN_BLOCK_SIZE: usize = mem::size_of::<Hole>();
+/// Every block handed out or taken back is aligned to at least this.
+pub const MIN_ALIGN: usize = mem::align_of::<Hole>();
+/// Bytes at the start of every free block that hold the list itself.
+pub const HOLE_HEADER_SIZE: usize = mem::size_of::<Hole>();
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// With `heap-debug`, free memory apart from the hole headers is kept filled with `FREE_POISON`,
+/// so writes to it can be detected when it is handed out again.
+#[cfg(feature = "heap-debug")]
+unsafe fn poison(addr: usize, size: usize) {
+    (addr as *mut u8).write_bytes(FREE_POISON, size);
+}
+#[cfg(not(feature = "heap-debug"))]
+unsafe fn poison(_addr: usize, _size: usize) {}
+
+/// Address ordered singly linked list of free memory blocks.
+///
+/// Adjacent free blocks are merged on deallocation, so the list never holds two blocks that
+/// touch each other.
+pub struct FreeList {
+    head: *mut Hole,
+    free: usize,
+}
+
+impl FreeList {
+    pub const fn empty() -> Self {
+        Self { head: ptr::null_mut(), free: 0 }
+    }
+
+    /// Round a requested size and alignment up to something every block can satisfy, this must
+    /// be applied to both allocation and deallocation requests.
+    pub fn block_layout(size: usize, align: usize) -> (usize, usize) {
+        let align = core::cmp::max(align, MIN_ALIGN);
+        let size = align_up(core::cmp::max(size, MIN_BLOCK_SIZE), MIN_ALIGN);
+        (size, align)
+    }
+
+    /// Amount of bytes currently in the free list.
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    /// Size of the largest free block.
+    pub fn largest_block(&self) -> usize {
+        let mut largest = 0;
+        let mut cursor = self.head;
+        while !cursor.is_null() {
+            unsafe {
+                if (*cursor).size > largest { largest = (*cursor).size; }
+                cursor = (*cursor).next;
+            }
+        }
+        largest
+    }
+
+    /// Amount of separate free blocks.
+    pub fn block_count(&self) -> usize {
+        let mut count = 0;
+        let mut cursor = self.head;
+        while !cursor.is_null() {
+            count += 1;
+            cursor = unsafe { (*cursor).next };
+        }
+        count
+    }
+
+    /// Call `f` with the address and size of every free block, in address order.
+    pub fn for_each_hole<F: FnMut(usize, usize)>(&self, mut f: F) {
+        let mut cursor = self.head;
+        while !cursor.is_null() {
+            unsafe {
+                f(cursor as usize, (*cursor).size);
+                cursor = (*cursor).next;
+            }
+        }
+    }
+
+    /// Take a block of `size` bytes aligned to `align` from the list, using first fit.
+    ///
+    /// `size` and `align` must have gone through `block_layout`.
+    pub unsafe fn allocate(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut Hole = ptr::null_mut();
+        let mut cursor = self.head;
+
+        while !cursor.is_null() {
+            let hole_start = cursor as usize;
+            let hole_end = hole_start + (*cursor).size;
+
+            // Padding in front of the block must be large enough to be a block of its own.
+            let mut start = align_up(hole_start, align);
+            if start != hole_start && start - hole_start < MIN_BLOCK_SIZE {
+                start = align_up(hole_start + MIN_BLOCK_SIZE, align);
+            }
+            let end = start.checked_add(size)?;
+
+            if end <= hole_end && (end == hole_end || hole_end - end >= MIN_BLOCK_SIZE) {
+                let next = (*cursor).next;
+                if prev.is_null() { self.head = next; } else { (*prev).next = next; }
+                self.free -= hole_end - hole_start;
+                poison(hole_start, HOLE_HEADER_SIZE);
+
+                if start > hole_start { self.deallocate(hole_start, start - hole_start); }
+                if hole_end > end { self.deallocate(end, hole_end - end); }
+                return Some(start);
+            }
+
+            prev = cursor;
+            cursor = (*cursor).next;
+        }
+        None
+    }
+
+    /// Move the whole list up by `offset`, for when its memory is mapped again at that offset.
+    ///
+    /// The memory must be accessible at both addresses while this runs.
+    pub unsafe fn relocate(&mut self, offset: usize) {
+        if self.head.is_null() { return; }
+        self.head = (self.head as usize + offset) as *mut Hole;
+
+        let mut cursor = self.head;
+        while !(*cursor).next.is_null() {
+            (*cursor).next = ((*cursor).next as usize + offset) as *mut Hole;
+            cursor = (*cursor).next;
+        }
+    }
+
+    /// Put a block back into the list, merging it with its neighbours.
+    ///
+    /// Panics if the block overlaps memory that is already free, which means it was freed twice.
+    pub unsafe fn deallocate(&mut self, addr: usize, size: usize) {
+        assert!(addr % MIN_ALIGN == 0 && size >= MIN_BLOCK_SIZE, "Invalid block 0x{:X} of {} bytes", addr, size);
+
+        let mut prev: *mut Hole = ptr::null_mut();
+        let mut cursor = self.head;
+        while !cursor.is_null() && (cursor as usize) < addr {
+            prev = cursor;
+            cursor = (*cursor).next;
+        }
+
+        if (!cursor.is_null() && addr + size > cursor as usize)
+                || (!prev.is_null() && prev as usize + (*prev).size > addr) {
+            panic!("Block 0x{:X} of {} bytes is already (partially) free, double free?", addr, size);
+        }
+        self.free += size;
+        poison(addr, size);
+
+        let mut size = size;
+        let mut next = cursor;
+        if !cursor.is_null() && addr + size == cursor as usize {
+            size += (*cursor).size;
+            next = (*cursor).next;
+            poison(cursor as usize, HOLE_HEADER_SIZE);
+        }
+
+        if !prev.is_null() && prev as usize + (*prev).size == addr {
+            (*prev).size += size;
+            (*prev).next = next;
+        } else {
+            let hole = addr as *mut Hole;
+            hole.write(Hole { size, next });
+            if prev.is_null() { self.head = hole; } else { (*prev).next = hole; }
+        }
+    }
+}
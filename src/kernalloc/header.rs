@@ -0,0 +1,115 @@
+use core::alloc::Layout;
+use core::mem;
+
+/// Owner of an allocation, as recorded in its header.
+#[allow(non_snake_case)]
+pub mod BlockType {
+    pub const UEFI: u8 = 0x10;
+    pub const STAT: u8 = 0x20;
+    pub const HEAP: u8 = 0x30;
+
+    pub fn name(ty: u8) -> &'static str {
+        match ty {
+            UEFI => "UefiAlloc",
+            STAT => "StatAlloc",
+            HEAP => "KernelHeap",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Written to the header of every live allocation.
+const MAGIC_LIVE: u32 = 0x4B41_4C43;
+/// Written to the header when an allocation is freed, to catch double frees.
+const MAGIC_FREED: u32 = 0x4652_4545;
+
+/// Header stored right in front of every pointer handed out by `kernalloc::Allocator`.
+///
+/// The backend's block looks like this, where padding makes sure the pointer is aligned and the
+/// red zones are only present with the `heap-debug` feature:
+///
+/// | padding | BlockHeader | red zone | user data | red zone |
+/// ^ block start                      ^ pointer, `offset` bytes after the block start
+#[repr(C)]
+pub struct BlockHeader {
+    magic: u32,
+    pub owner: u8,
+    _reserved: [u8; 3],
+    /// Distance from the start of the backend's block to the user pointer.
+    pub offset: usize,
+    /// Size as requested by the user.
+    pub size: usize,
+    /// Alignment as requested by the user.
+    pub align: usize,
+}
+
+pub const HEADER_SIZE: usize = mem::size_of::<BlockHeader>();
+
+/// Guard bytes between the header and the user data, and after the user data.
+#[cfg(feature = "heap-debug")]
+pub const REDZONE: usize = 16;
+#[cfg(not(feature = "heap-debug"))]
+pub const REDZONE: usize = 0;
+
+impl BlockHeader {
+    /// Distance between the start of a block and the pointer handed out for the given layout.
+    pub fn offset_for(layout: &Layout) -> usize {
+        let align = layout.align();
+        (HEADER_SIZE + REDZONE + align - 1) & !(align - 1)
+    }
+
+    /// The layout that has to be requested from a backend to serve `layout`, header included.
+    pub fn block_layout(layout: &Layout) -> Option<Layout> {
+        let size = layout.size().checked_add(Self::offset_for(layout) + REDZONE)?;
+        let align = core::cmp::max(layout.align(), mem::align_of::<Self>());
+        Layout::from_size_align(size, align).ok()
+    }
+
+    /// Write a header for a fresh block, returning the pointer to hand out.
+    pub unsafe fn write(block: *mut u8, layout: &Layout, owner: u8) -> *mut u8 {
+        let offset = Self::offset_for(layout);
+        let ptr = block.add(offset);
+        (ptr.sub(REDZONE + HEADER_SIZE) as *mut BlockHeader).write(BlockHeader {
+            magic: MAGIC_LIVE,
+            owner,
+            _reserved: [0; 3],
+            offset,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr
+    }
+
+    /// The header belonging to a pointer handed out by `write`, which is not checked in any way.
+    pub unsafe fn of<'a>(ptr: *mut u8) -> &'a mut BlockHeader {
+        &mut *(ptr.sub(REDZONE + HEADER_SIZE) as *mut BlockHeader)
+    }
+
+    /// Whether the header looks like the header of a live allocation.
+    pub fn is_live(&self) -> bool {
+        self.magic == MAGIC_LIVE
+    }
+
+    /// Look up the header of a pointer that is about to be freed, panicking if it does not look
+    /// like a live allocation matching `layout`.
+    pub unsafe fn validate<'a>(ptr: *mut u8, layout: &Layout) -> &'a mut BlockHeader {
+        let header = Self::of(ptr);
+        match header.magic {
+            MAGIC_LIVE => {}
+            MAGIC_FREED => panic!("Double free of {:?} ({} bytes, align {})", ptr, layout.size(), layout.align()),
+            magic => panic!("Heap corruption: invalid block header magic 0x{:08X} for {:?} ({} bytes, align {})",
+                magic, ptr, layout.size(), layout.align()),
+        }
+        if header.size != layout.size() || header.align != layout.align() || header.offset != Self::offset_for(layout) {
+            panic!("Heap corruption: block header of {:?} says {} bytes align {} at offset {}, but freed with {} bytes align {}",
+                ptr, header.size, header.align, header.offset, layout.size(), layout.align());
+        }
+        header
+    }
+
+    /// Mark the block as freed and return the start of the backend's block.
+    pub unsafe fn retire(&mut self, ptr: *mut u8) -> *mut u8 {
+        self.magic = MAGIC_FREED;
+        ptr.sub(self.offset)
+    }
+}
@@ -0,0 +1,107 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+
+use log::{debug, info, warn, error};
+
+use super::free_list::FreeList;
+
+/// Backs `size` bytes of virtual memory starting at `start` with fresh physical memory.
+///
+/// Returns false if that's not possible, in which case nothing may have been mapped.
+pub type GrowFn = unsafe fn(start: usize, size: usize) -> bool;
+
+/// The heap never grows by less than this at a time.
+const GROW_STEP: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+struct Inner {
+    /// End of the part of the heap that is backed by memory.
+    end: usize,
+    free: FreeList,
+}
+
+/// Growable kernel heap living in a fixed range of kernel virtual memory.
+///
+/// Only the part of the range that is actually needed is mapped, more is requested through the
+/// `grow` function whenever the free list can't satisfy an allocation.
+pub struct KernelHeap {
+    start: usize,
+    max_size: usize,
+    grow: GrowFn,
+    inner: UnsafeCell<Inner>,
+}
+
+// TODO implement thread safety.. but we first need synchronization primitives.
+unsafe impl Sync for KernelHeap {}
+
+impl KernelHeap {
+    pub fn new(start: usize, max_size: usize, grow: GrowFn) -> Self {
+        assert!(start % PAGE_SIZE == 0, "Kernel heap must start on a page boundary.");
+        Self {
+            start,
+            max_size,
+            grow,
+            inner: UnsafeCell::new(Inner { end: start, free: FreeList::empty() }),
+        }
+    }
+
+    /// Whether the given pointer lies within this heap's virtual address range.
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let addr = ptr as usize;
+        addr >= self.start && addr < self.start + self.max_size
+    }
+
+    /// Amount of bytes of the heap that are backed by memory.
+    pub fn mapped_size(&self) -> usize {
+        unsafe { (*self.inner.get()).end - self.start }
+    }
+
+    /// Bytes in the mapped part of the heap that are free.
+    pub fn free_bytes(&self) -> usize {
+        unsafe { (*self.inner.get()).free.free_bytes() }
+    }
+
+    /// Size of the largest free block in the mapped part of the heap.
+    pub fn largest_free_block(&self) -> usize {
+        unsafe { (*self.inner.get()).free.largest_block() }
+    }
+
+    /// Call `f` with the address and size of every free block, see `FreeList::for_each_hole`.
+    pub fn for_each_hole<F: FnMut(usize, usize)>(&self, f: F) {
+        unsafe { (*self.inner.get()).free.for_each_hole(f) }
+    }
+
+    unsafe fn grow_by(&self, inner: &mut Inner, needed: usize) -> bool {
+        let amount = (core::cmp::max(needed, GROW_STEP) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if inner.end + amount > self.start + self.max_size { return false; }
+        if !(self.grow)(inner.end, amount) { return false; }
+
+        debug!("Grew kernel heap by {} bytes at 0x{:X}", amount, inner.end);
+        inner.free.deallocate(inner.end, amount);
+        inner.end += amount;
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let inner = &mut *self.inner.get();
+        let (size, align) = FreeList::block_layout(layout.size(), layout.align());
+
+        if let Some(addr) = inner.free.allocate(size, align) {
+            return addr as *mut u8;
+        }
+        // Worst case the block has to be placed after alignment padding at the end of the heap.
+        if !self.grow_by(inner, size + align) {
+            return ptr::null_mut();
+        }
+        inner.free.allocate(size, align).map(|addr| addr as *mut u8).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let inner = &mut *self.inner.get();
+        let (size, _) = FreeList::block_layout(layout.size(), layout.align());
+        inner.free.deallocate(ptr as usize, size);
+    }
+}
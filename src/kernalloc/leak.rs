@@ -0,0 +1,160 @@
+//! Leak tracking, records a tag for every live allocation so the allocations that were never
+//! freed can be reported.
+//!
+//! Tags are set with `kernalloc::tag`, every allocation made while a tag is active is attributed
+//! to it.
+
+use log::{debug, info, warn, error};
+
+use byte_unit::*;
+
+/// Maximum amount of live allocations that can be tracked at the same time.
+const CAPACITY: usize = 4096;
+/// Maximum amount of distinct tags shown in a leak report.
+const MAX_REPORTED_TAGS: usize = 32;
+
+const EMPTY: usize = 0;
+
+pub const UNTAGGED: &str = "untagged";
+
+#[derive(Clone, Copy)]
+struct Entry {
+    ptr: usize,
+    size: usize,
+    tag: &'static str,
+}
+
+/// Open addressing hash table of live allocations, keyed by pointer, with linear probing. Entries
+/// are removed by shifting the ones after them back, so there are no tombstones to pile up.
+static mut LIVE: [Entry; CAPACITY] = [Entry { ptr: EMPTY, size: 0, tag: UNTAGGED }; CAPACITY];
+static mut LIVE_COUNT: usize = 0;
+/// Allocations that could not be tracked because the table was full.
+static mut UNTRACKED: u64 = 0;
+static mut CURRENT_TAG: &str = UNTAGGED;
+
+/// Restores the previous tag when dropped.
+pub struct TagGuard {
+    previous: &'static str,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        unsafe { CURRENT_TAG = self.previous; }
+    }
+}
+
+/// Attribute all allocations made until the returned guard is dropped to `tag`.
+pub fn tag(tag: &'static str) -> TagGuard {
+    unsafe {
+        let previous = CURRENT_TAG;
+        CURRENT_TAG = tag;
+        TagGuard { previous }
+    }
+}
+
+fn slot(ptr: usize) -> usize {
+    // Allocations are at least 8 byte aligned, the low bits carry no information.
+    (ptr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) % CAPACITY
+}
+
+pub unsafe fn track(ptr: *mut u8, size: usize) {
+    if LIVE_COUNT >= CAPACITY - 1 {
+        UNTRACKED += 1;
+        return;
+    }
+    insert(Entry { ptr: ptr as usize, size, tag: CURRENT_TAG });
+}
+
+unsafe fn insert(entry: Entry) {
+    let mut i = slot(entry.ptr);
+    while LIVE[i].ptr != EMPTY {
+        i = (i + 1) % CAPACITY;
+    }
+    LIVE[i] = entry;
+    LIVE_COUNT += 1;
+}
+
+/// Move every tracked pointer below `offset` up by `offset`, see `kernalloc::move_to_higher_half`.
+pub unsafe fn relocate(offset: usize) {
+    let mut i = 0;
+    while i < CAPACITY {
+        let entry = LIVE[i];
+        if entry.ptr != EMPTY && entry.ptr < offset {
+            // Removing may shift a later entry into this slot, so look at it again. The moved
+            // entry is never relocated twice because it no longer lies below `offset`.
+            remove_at(i);
+            insert(Entry { ptr: entry.ptr + offset, ..entry });
+        } else {
+            i += 1;
+        }
+    }
+}
+
+pub unsafe fn untrack(ptr: *mut u8) {
+    let mut i = slot(ptr as usize);
+    for _ in 0..CAPACITY {
+        match LIVE[i].ptr {
+            EMPTY => return,
+            p if p == ptr as usize => {
+                remove_at(i);
+                return;
+            }
+            _ => i = (i + 1) % CAPACITY,
+        }
+    }
+}
+
+/// Empty slot `hole`, shifting back entries after it that would no longer be found otherwise.
+unsafe fn remove_at(mut hole: usize) {
+    LIVE_COUNT -= 1;
+    let mut i = hole;
+    loop {
+        i = (i + 1) % CAPACITY;
+        if LIVE[i].ptr == EMPTY { break; }
+        // An entry can fill the hole if the hole lies between its home slot and where it is now.
+        let home = slot(LIVE[i].ptr);
+        if (i + CAPACITY - home) % CAPACITY >= (i + CAPACITY - hole) % CAPACITY {
+            LIVE[hole] = LIVE[i];
+            hole = i;
+        }
+    }
+    LIVE[hole].ptr = EMPTY;
+}
+
+/// Call `f` with the pointer and size of every tracked live allocation.
+pub fn for_each_live<F: FnMut(*mut u8, usize)>(mut f: F) {
+    unsafe {
+        for entry in LIVE.iter().filter(|it| it.ptr != EMPTY) {
+            f(entry.ptr as *mut u8, entry.size);
+        }
+    }
+}
+
+/// Dump all live allocations, grouped by tag, over the kernel log.
+pub fn report() {
+    let mut tags: heapless::LinearMap<&'static str, (usize, usize), heapless::consts::U32> = heapless::LinearMap::new();
+    let mut other = (0usize, 0usize);
+
+    unsafe {
+        for entry in LIVE.iter().filter(|it| it.ptr != EMPTY) {
+            match tags.get_mut(&entry.tag) {
+                Some(v) => { v.0 += 1; v.1 += entry.size; }
+                None => if tags.len() < MAX_REPORTED_TAGS {
+                    tags.insert(entry.tag, (1, entry.size)).ok();
+                } else {
+                    other.0 += 1;
+                    other.1 += entry.size;
+                }
+            }
+        }
+
+        info!("Leak report: {} live allocations, {} untracked", LIVE_COUNT, UNTRACKED);
+    }
+
+    for (tag, (count, bytes)) in tags.iter() {
+        info!("\t{}: {} allocations, {}", tag, count, Byte::from_bytes(*bytes as u128).get_appropriate_unit(true));
+    }
+    if other.0 != 0 {
+        info!("\tother tags: {} allocations, {}", other.0, Byte::from_bytes(other.1 as u128).get_appropriate_unit(true));
+    }
+}
@@ -11,31 +11,72 @@ use log::{debug, info, warn, error};
 
 use byte_unit::*;
 
+use crate::bootconfig::BootConfig;
+
 mod uefi_alloc;
 mod stat_alloc;
+mod free_list;
+mod kernel_heap;
+mod header;
+mod stats;
+mod oom;
+#[cfg(feature = "leak-tracking")]
+mod leak;
+#[cfg(feature = "heap-debug")]
+mod debug;
 
 use uefi_alloc::UefiAlloc;
 use stat_alloc::StatAlloc;
+use kernel_heap::KernelHeap;
+use header::BlockHeader;
+
+pub use kernel_heap::GrowFn;
+pub use stat_alloc::StatAllocStats;
+pub use header::BlockType;
+pub use stats::AllocStats;
+pub use oom::{ReclaimFn, register_reclaimer, register_reporter as register_oom_reporter};
+#[cfg(feature = "leak-tracking")]
+pub use leak::{tag, report as leak_report};
+#[cfg(feature = "heap-debug")]
+pub use debug::heap_check;
 
 pub struct Allocator;
 
-mod BlockType {
-    pub const UEFI: u8 = 0x10;
-    pub const STAT: u8 = 0x20;
+/// Heap backend to use once paging is up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapBackend {
+    /// The growable kernel heap.
+    Kernel,
+    /// Keep using the emergency heap, mostly useful for debugging the kernel heap.
+    Emergency,
 }
 
 static mut emergency_heap: Option<*mut u8> = None;
 static mut uefialloc: Option<UefiAlloc> = None;
 static mut statalloc: Option<StatAlloc> = None;
+static mut kernelheap: Option<KernelHeap> = None;
+static mut heapbackend: HeapBackend = HeapBackend::Kernel;
+static mut backendstats: [AllocStats; 3] = [AllocStats::new(), AllocStats::new(), AllocStats::new()];
+
+fn stats_mut(owner: u8) -> &'static mut AllocStats {
+    unsafe {
+        match owner {
+            BlockType::UEFI => &mut backendstats[0],
+            BlockType::STAT => &mut backendstats[1],
+            _ => &mut backendstats[2],
+        }
+    }
+}
 
 
-pub unsafe fn init(bs: &'static Option<&BootServices>) -> Result<(), ()> {
-    let pool_size = n_mib_bytes!(1) as usize;
-    let pool = bs.unwrap().allocate_pool(MemoryType::LOADER_DATA, pool_size).unwrap();
+pub unsafe fn init(bs: &'static Option<&BootServices>, config: &BootConfig) -> Result<(), ()> {
+    let pool_size = config.emergency_heap_size;
+    let pool = bs.unwrap().allocate_pool(MemoryType::LOADER_DATA, pool_size).map_err(|_| ())?;
     unsafe { emergency_heap = Some(pool.unwrap() as *mut u8); }
-    info!("Initializing kernalloc.");
+    info!("Initializing kernalloc with a {} emergency heap.", Byte::from_bytes(pool_size as u128).get_appropriate_unit(true));
     uefialloc = Some(UefiAlloc::new(bs));
     statalloc = Some(StatAlloc::new(emergency_heap.unwrap(), pool_size));
+    heapbackend = config.heap_backend;
     Ok(())
 }
 
@@ -44,37 +85,180 @@ pub unsafe fn exit_boot_services() {
     uefialloc = None;
 }
 
+/// Move every pointer kernalloc holds that lies below `offset` up by `offset`, for when all memory
+/// handed to it so far, which was identity mapped, is mapped again at `offset` above that.
+///
+/// Pointers stored inside allocations are not touched, so anything that is still allocated at
+/// this point should not hold any.
+pub unsafe fn move_to_higher_half(offset: usize) {
+    if let Some(pool) = emergency_heap {
+        if (pool as usize) < offset {
+            emergency_heap = Some(pool.add(offset));
+            statalloc.as_mut().unwrap().relocate(offset);
+        }
+    }
+    #[cfg(feature = "leak-tracking")]
+    leak::relocate(offset);
+}
+
+/// Switch over to the growable kernel heap, to be called once paging is up.
+///
+/// `start` up to `start + max_size` must be unused kernel virtual memory, `grow` is used to back
+/// it with physical memory on demand.
+pub unsafe fn init_kernel_heap(start: usize, max_size: usize, grow: GrowFn) {
+    if heapbackend == HeapBackend::Emergency {
+        info!("Kernel heap disabled by boot config, staying on the emergency heap.");
+        return;
+    }
+    info!("Initializing kernel heap at 0x{:X}, up to {}.", start, Byte::from_bytes(max_size as u128).get_appropriate_unit(true));
+    kernelheap = Some(KernelHeap::new(start, max_size, grow));
+}
+
+/// Usage statistics of the emergency heap.
+pub fn emergency_heap_stats() -> Option<StatAllocStats> {
+    unsafe { statalloc.as_ref().map(|it| it.stats()) }
+}
+
+/// Counters of the given backend, see `BlockType`.
+pub fn stats(owner: u8) -> AllocStats {
+    *stats_mut(owner)
+}
+
+/// Dump the counters of every backend over the kernel log.
+pub fn log_stats() {
+    for owner in [BlockType::UEFI, BlockType::STAT, BlockType::HEAP].iter() {
+        stats_mut(*owner).log(BlockType::name(*owner));
+    }
+}
+
+/// Find the backend owning the given pointer by its address alone.
+///
+/// Returns None for pointers that don't belong to any backend with a fixed address range, which
+/// includes everything allocated from UEFI pools.
+pub fn owner_by_address(ptr: *const u8) -> Option<u8> {
+    unsafe {
+        if kernelheap.as_ref().map(|it| it.contains(ptr as *mut u8)).unwrap_or(false) {
+            Some(BlockType::HEAP)
+        } else if statalloc.as_ref().map(|it| it.contains(ptr as *mut u8)).unwrap_or(false) {
+            Some(BlockType::STAT)
+        } else {
+            None
+        }
+    }
+}
+
+/// Allocate a block from the currently active backend.
+unsafe fn alloc_block(block_layout: Layout) -> (*mut u8, u8) {
+    if let Some(allocator) = kernelheap.as_ref() {
+        (allocator.alloc(block_layout), BlockType::HEAP)
+    } else {
+        match uefialloc.as_ref() {
+            Some(allocator) => (allocator.alloc(block_layout), BlockType::UEFI),
+            None => {
+                (statalloc.as_ref().unwrap().alloc(block_layout), BlockType::STAT)
+            }
+        }
+    }
+}
+
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mem_ty = MemoryType::LOADER_DATA;
-        let size = layout.size();
-        let align = layout.align();
+        let block_layout = match BlockHeader::block_layout(&layout) {
+            Some(v) => v,
+            None => return ptr::null_mut(),
+        };
 
-        // TODO: add support for other alignments.
-        if align > 8 {
+        let (mut block, owner) = alloc_block(block_layout);
+        if block.is_null() && oom::try_reclaim(&block_layout) {
+            block = alloc_block(block_layout).0;
+        }
+
+        if block.is_null() {
+            stats_mut(owner).record_failure(&layout);
             ptr::null_mut()
         } else {
-            match uefialloc.as_ref() {
-                Some(allocator) => allocator.alloc(layout),
-                None => {
-                    statalloc.as_ref().unwrap().alloc(layout)
+            // UEFI pool memory does not come from one of our free lists, so it isn't poisoned.
+            #[cfg(feature = "heap-debug")]
+            {
+                if owner != BlockType::UEFI && !debug::check_free(block, block_layout.size()) {
+                    panic!("Heap corruption detected while allocating {:?} ({} bytes, align {})", block, layout.size(), layout.align());
                 }
             }
+
+            stats_mut(owner).record_alloc(&layout);
+            let ptr = BlockHeader::write(block, &layout, owner);
+            #[cfg(feature = "heap-debug")]
+            debug::arm(ptr, layout.size());
+            #[cfg(feature = "leak-tracking")]
+            leak::track(ptr, layout.size());
+            ptr
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let ty: u8 = *ptr.offset(-8) & 0xF0; // most-significant half-byte of the 64 bit integer right before the buffer
+        // The header tells where the red zones are, so it has to be sane before they are checked.
+        let header = BlockHeader::validate(ptr, &layout);
+        #[cfg(feature = "heap-debug")]
+        {
+            if !debug::check(ptr) {
+                panic!("Heap corruption detected while freeing {:?} ({} bytes, align {})", ptr, layout.size(), layout.align());
+            }
+            debug::poison(ptr, layout.size());
+        }
 
-        match ty {
-            BlockType::UEFI => if let Some(allocator) = uefialloc.as_ref() { allocator.dealloc(ptr, layout) }
-            _ => {}
+        let owner = header.owner;
+        if let Some(expected) = owner_by_address(ptr) {
+            if expected != owner {
+                panic!("Heap corruption: {:?} lies in {} but its header claims it belongs to {}",
+                    ptr, BlockType::name(expected), BlockType::name(owner));
+            }
+        }
+
+        stats_mut(owner).record_free(&layout);
+        #[cfg(feature = "leak-tracking")]
+        leak::untrack(ptr);
+
+        let block = header.retire(ptr);
+        let block_layout = BlockHeader::block_layout(&layout).unwrap();
+        match owner {
+            BlockType::HEAP => kernelheap.as_ref().unwrap().dealloc(block, block_layout),
+            // Pool memory handed out before ExitBootServices simply stays allocated afterwards.
+            BlockType::UEFI => if let Some(allocator) = uefialloc.as_ref() { allocator.dealloc(block, block_layout) },
+            BlockType::STAT => statalloc.as_ref().unwrap().dealloc(block, block_layout),
+            _ => panic!("Heap corruption: {:?} has unknown owner 0x{:02X}", ptr, owner),
         }
     }
 }
 
+/// Dump the state of every allocator over the kernel log.
+pub fn log_state() {
+    log_stats();
+
+    if let Some(stats) = emergency_heap_stats() {
+        info!("Emergency heap: {} used ({} peak), {} free in {} blocks, largest free block {}, {}% fragmented",
+            Byte::from_bytes(stats.used as u128).get_appropriate_unit(true),
+            Byte::from_bytes(stats.peak as u128).get_appropriate_unit(true),
+            Byte::from_bytes(stats.free as u128).get_appropriate_unit(true),
+            stats.free_blocks,
+            Byte::from_bytes(stats.largest_free as u128).get_appropriate_unit(true),
+            stats.fragmentation);
+    }
+
+    if let Some(heap) = unsafe { kernelheap.as_ref() } {
+        info!("Kernel heap: {} mapped, {} free, largest free block {}",
+            Byte::from_bytes(heap.mapped_size() as u128).get_appropriate_unit(true),
+            Byte::from_bytes(heap.free_bytes() as u128).get_appropriate_unit(true),
+            Byte::from_bytes(heap.largest_free_block() as u128).get_appropriate_unit(true));
+    }
+
+    oom::report_external();
+}
+
 #[alloc_error_handler]
 fn out_of_memory(layout: Layout) -> ! {
+    error!("Out of memory while trying to allocate {} bytes aligned to {}, heap state:", layout.size(), layout.align());
+    log_state();
+
     panic!(
         "Ran out of free memory while trying to allocate {:#?}",
         layout
@@ -0,0 +1,55 @@
+//! Out of memory handling: reclaim callbacks that are given a chance to free memory before an
+//! allocation fails for good, and a report of the state of all allocators once it does.
+
+use core::alloc::Layout;
+
+use log::{debug, info, warn, error};
+
+use byte_unit::*;
+
+/// Tries to free at least `needed` bytes, returning the amount of bytes actually freed.
+pub type ReclaimFn = fn(needed: usize) -> usize;
+
+const MAX_RECLAIMERS: usize = 16;
+
+static mut RECLAIMERS: [Option<(&str, ReclaimFn)>; MAX_RECLAIMERS] = [None; MAX_RECLAIMERS];
+/// Dumps the state of memory managers kernalloc itself does not know about, such as the physical
+/// frame allocator.
+static mut REPORTER: Option<fn()> = None;
+
+/// Register a callback that can free memory, such as a cache that can be dropped.
+pub fn register_reclaimer(name: &'static str, reclaim: ReclaimFn) -> Result<(), ()> {
+    unsafe {
+        let slot = RECLAIMERS.iter_mut().find(|it| it.is_none()).ok_or(())?;
+        *slot = Some((name, reclaim));
+    }
+    Ok(())
+}
+
+/// Register a function that reports the state of an external memory manager when we run out of
+/// memory.
+pub fn register_reporter(report: fn()) {
+    unsafe { REPORTER = Some(report); }
+}
+
+/// Give all reclaimers a chance to free memory, returns whether any of them did.
+pub fn try_reclaim(layout: &Layout) -> bool {
+    let mut freed = 0;
+    unsafe {
+        for (name, reclaim) in RECLAIMERS.iter().filter_map(|it| *it) {
+            let amount = reclaim(layout.size());
+            if amount > 0 {
+                info!("Reclaimer '{}' freed {}.", name, Byte::from_bytes(amount as u128).get_appropriate_unit(true));
+            }
+            freed += amount;
+        }
+    }
+    freed > 0
+}
+
+/// Run the external reporter, if any.
+pub fn report_external() {
+    if let Some(report) = unsafe { REPORTER } {
+        report();
+    }
+}
@@ -1,56 +1,108 @@
-
 use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
 use core::ptr;
 
-use uefi::prelude::*;
-use uefi::table::boot::{BootServices, MemoryType };
+use super::free_list::{self, FreeList};
+
+/// Snapshot of the state of a `StatAlloc`.
+#[derive(Debug, Clone, Copy)]
+pub struct StatAllocStats {
+    /// Bytes currently handed out, including free list rounding.
+    pub used: usize,
+    /// Highest value `used` has ever had.
+    pub peak: usize,
+    /// Bytes available for allocation.
+    pub free: usize,
+    /// Size of the largest single allocation that could currently succeed, ignoring alignment.
+    pub largest_free: usize,
+    /// Amount of separate free blocks.
+    pub free_blocks: usize,
+    /// Percentage of free memory that is not part of the largest free block.
+    pub fragmentation: usize,
+}
+
+struct Inner {
+    free: FreeList,
+    used: usize,
+    peak: usize,
+}
 
+/// Small free-list allocator over a fixed memory pool, serving as the emergency heap.
 pub struct StatAlloc {
     buf: *mut u8,
     maxsize: usize,
-    used: usize,
+    inner: UnsafeCell<Inner>,
 }
 
+// TODO implement thread safety.. but we first need synchronization primitives.
+unsafe impl Sync for StatAlloc {}
+
 impl StatAlloc {
     pub fn new(buf: *mut u8, maxsize: usize) -> Self {
-        assert!(maxsize != 0, "maxsize should be at least 1 or more.");
+        assert!(maxsize >= free_list::MIN_BLOCK_SIZE, "maxsize should be at least {} or more.", free_list::MIN_BLOCK_SIZE);
+        assert!((buf as usize) % free_list::MIN_ALIGN == 0, "buf should be aligned to {} bytes.", free_list::MIN_ALIGN);
+
+        let mut free = FreeList::empty();
+        let usable = maxsize & !(free_list::MIN_ALIGN - 1);
+        unsafe { free.deallocate(buf as usize, usable); }
+
         Self {
             buf: buf,
             maxsize: maxsize,
-            used: 0
+            inner: UnsafeCell::new(Inner { free, used: 0, peak: 0 }),
+        }
+    }
+
+    /// Whether the given pointer lies within the pool managed by this allocator.
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        ptr >= self.buf && (ptr as usize) < (self.buf as usize) + self.maxsize
+    }
+
+    /// Move the pool up by `offset`, see `FreeList::relocate`.
+    pub unsafe fn relocate(&mut self, offset: usize) {
+        self.buf = self.buf.add(offset);
+        (*self.inner.get()).free.relocate(offset);
+    }
+
+    /// Call `f` with the address and size of every free block, see `FreeList::for_each_hole`.
+    pub fn for_each_hole<F: FnMut(usize, usize)>(&self, f: F) {
+        unsafe { (*self.inner.get()).free.for_each_hole(f) }
+    }
+
+    pub fn stats(&self) -> StatAllocStats {
+        let inner = unsafe { &*self.inner.get() };
+        let free = inner.free.free_bytes();
+        let largest_free = inner.free.largest_block();
+        StatAllocStats {
+            used: inner.used,
+            peak: inner.peak,
+            free,
+            largest_free,
+            free_blocks: inner.free.block_count(),
+            fragmentation: if free == 0 { 0 } else { (free - largest_free) * 100 / free },
         }
     }
 }
 
 unsafe impl GlobalAlloc for StatAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // TODO: add support for other alignments.
-        let align = layout.align();
-        let size = layout.size();
-        let mutself = self as *const StatAlloc;
-        let mutself = mutself as *mut StatAlloc;
-        let mutself = mutself.as_mut().unwrap();
-
-        if !align.is_power_of_two() {
-            ptr::null_mut()
-        } else {
-            let mut last = self.buf.add(self.maxsize);
-            let mut cursor = self.buf.add(self.used);
-            if cursor > last { return ptr::null_mut(); }
-            *cursor = 0x20;
-            cursor = cursor.add(8);
-            if cursor > last { return ptr::null_mut(); }
-            let offset = cursor.align_offset(align);
-            if offset == usize::max_value() { return ptr::null_mut(); }
-            cursor = cursor.add(offset);
-
-            mutself.used += (cursor as usize) - (self.buf as usize) + size;
-            cursor
+        let inner = &mut *self.inner.get();
+        let (size, align) = FreeList::block_layout(layout.size(), layout.align());
+
+        match inner.free.allocate(size, align) {
+            Some(addr) => {
+                inner.used += size;
+                if inner.used > inner.peak { inner.peak = inner.used; }
+                addr as *mut u8
+            }
+            None => ptr::null_mut(),
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        // does nothing, we don't dealloc
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let inner = &mut *self.inner.get();
+        let (size, _) = FreeList::block_layout(layout.size(), layout.align());
+        inner.free.deallocate(ptr as usize, size);
+        inner.used -= size;
     }
 }
-
@@ -0,0 +1,84 @@
+use core::alloc::Layout;
+
+use log::{debug, info, warn, error};
+
+use byte_unit::*;
+
+/// Failed allocations are bucketed by the log2 of their size, the last bucket catching the rest.
+pub const SIZE_CLASSES: usize = 24;
+/// Failed allocations are bucketed by the log2 of their alignment, the last bucket catching the
+/// rest.
+pub const ALIGN_CLASSES: usize = 16;
+
+/// Counters kept for every kernalloc backend.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocStats {
+    pub allocations: u64,
+    pub frees: u64,
+    /// Bytes requested by allocations that have not been freed yet, excluding headers.
+    pub live_bytes: usize,
+    /// Highest value `live_bytes` has ever had.
+    pub peak_bytes: usize,
+    pub failed: u64,
+    pub failed_by_size: [u64; SIZE_CLASSES],
+    pub failed_by_align: [u64; ALIGN_CLASSES],
+}
+
+fn log2_class(value: usize, classes: usize) -> usize {
+    let class = if value <= 1 { 0 } else { (64 - (value - 1).leading_zeros()) as usize };
+    core::cmp::min(class, classes - 1)
+}
+
+impl AllocStats {
+    pub const fn new() -> Self {
+        Self {
+            allocations: 0,
+            frees: 0,
+            live_bytes: 0,
+            peak_bytes: 0,
+            failed: 0,
+            failed_by_size: [0; SIZE_CLASSES],
+            failed_by_align: [0; ALIGN_CLASSES],
+        }
+    }
+
+    pub fn record_alloc(&mut self, layout: &Layout) {
+        self.allocations += 1;
+        self.live_bytes += layout.size();
+        if self.live_bytes > self.peak_bytes { self.peak_bytes = self.live_bytes; }
+    }
+
+    pub fn record_free(&mut self, layout: &Layout) {
+        self.frees += 1;
+        self.live_bytes -= layout.size();
+    }
+
+    pub fn record_failure(&mut self, layout: &Layout) {
+        self.failed += 1;
+        self.failed_by_size[log2_class(layout.size(), SIZE_CLASSES)] += 1;
+        self.failed_by_align[log2_class(layout.align(), ALIGN_CLASSES)] += 1;
+    }
+
+    /// Dump these statistics over the kernel log.
+    pub fn log(&self, name: &str) {
+        info!("{}: {} allocations, {} frees, {} live ({} peak), {} failed",
+            name,
+            self.allocations,
+            self.frees,
+            Byte::from_bytes(self.live_bytes as u128).get_appropriate_unit(true),
+            Byte::from_bytes(self.peak_bytes as u128).get_appropriate_unit(true),
+            self.failed);
+
+        for (class, count) in self.failed_by_size.iter().enumerate().filter(|it| *it.1 != 0) {
+            if class == SIZE_CLASSES - 1 {
+                info!("\tfailed with size > {}: {}", 1usize << (class - 1), count);
+            } else {
+                info!("\tfailed with size <= {}: {}", 1usize << class, count);
+            }
+        }
+        for (class, count) in self.failed_by_align.iter().enumerate().filter(|it| *it.1 != 0) {
+            let prefix = if class == ALIGN_CLASSES - 1 { ">=" } else { "" };
+            info!("\tfailed with align {}{}: {}", prefix, 1usize << class, count);
+        }
+    }
+}
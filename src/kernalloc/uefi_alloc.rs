@@ -14,32 +14,39 @@ impl<'a> UefiAlloc<'a> {
     }
 }
 
+/// Room in front of every block for the pointer UEFI returned.
+const PREFIX_SIZE: usize = 8;
+
 unsafe impl<'a> GlobalAlloc for UefiAlloc<'a> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let ty = MemoryType::LOADER_DATA;
 
-        // TODO: add support for other alignments.
-        if layout.align() > 8 {
-            // Unsupported alignment for allocation, UEFI can only allocate 8-byte aligned addresses
-            ptr::null_mut()
-        } else {
-            match self.boot_services.as_ref() {
-                Some(bs) => {
-                    let buf = bs.allocate_pool(ty, layout.size())
-                            .warning_as_error()
-                            .unwrap_or(ptr::null_mut());
-                    *(buf as *mut u64) = 0x100000000000000;
-                    buf.offset(8)
-                    // TODO prepend allocate type
-                }
-                None => ptr::null_mut()
+        match self.boot_services.as_ref() {
+            Some(bs) => {
+                // UEFI pools are only 8-byte aligned, so over-allocate and align within the block.
+                let align = core::cmp::max(layout.align(), 8);
+                let size = match layout.size().checked_add(PREFIX_SIZE + align - 8) {
+                    Some(size) => size,
+                    None => return ptr::null_mut(),
+                };
+                let buf = bs.allocate_pool(ty, size)
+                        .warning_as_error()
+                        .unwrap_or(ptr::null_mut());
+                if buf.is_null() { return ptr::null_mut(); }
+
+                let ptr = buf.add(PREFIX_SIZE);
+                let ptr = ptr.add(ptr.align_offset(align));
+                *(ptr.sub(PREFIX_SIZE) as *mut *mut u8) = buf;
+                ptr
             }
+            None => ptr::null_mut()
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let buf = *(ptr.sub(PREFIX_SIZE) as *const *mut u8);
         self.boot_services.as_ref().unwrap()
-            .free_pool(ptr)
+            .free_pool(buf)
             .warning_as_error()
             .unwrap();
     }
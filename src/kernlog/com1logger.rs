@@ -20,13 +20,16 @@ impl Com1Logger {
 
 impl log::Log for Com1Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
             let writer = unsafe { self.port.get().as_mut().unwrap() };
-            write!(writer, "{} - {}\n", record.level(), record.args());
+            match crate::wallclock::now() {
+                Some(now) => write!(writer, "[{}] {} - {}\n", now, record.level(), record.args()),
+                None => write!(writer, "{} - {}\n", record.level(), record.args()),
+            };
         }
     }
 
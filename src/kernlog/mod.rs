@@ -33,7 +33,7 @@ impl KernLogger {
 
 impl log::Log for KernLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
@@ -51,3 +51,8 @@ pub fn init() {
     log::set_logger(unsafe { LOGGER.as_ref().unwrap() })
             .map(|()| log::set_max_level(LevelFilter::Info));
 }
+
+/// Change the most verbose level that still gets logged.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
@@ -2,6 +2,7 @@
 #![no_main]
 
 #![feature(abi_efiapi)]
+#![feature(global_asm)]
 #![feature(panic_info_message)]
 #![feature(asm)]
 #![feature(alloc_error_handler)]
@@ -29,20 +30,29 @@ use core::slice;
 use log::{info, warn, error};
 
 use uefi::prelude::*;
-use uefi::table::boot::{MemoryType, MemoryDescriptor};
+use uefi::table::boot::{AllocateType, MemoryType, MemoryDescriptor};
+use uefi::table::cfg::{ACPI_GUID, ACPI2_GUID};
 
 
 mod arch;
 mod kernlog;
 mod kernalloc;
+mod bootconfig;
+mod memory_map;
+mod wallclock;
 
+use bootconfig::BootConfig;
+use memory_map::MemoryMap;
 
 
 
-pub const MAX_MEMORY_DESCRIPTOR_COUNT: usize = 200;
 
 static mut BOOT_SERVICES: Option<&BootServices> = None;
 static mut RUNTIME_SERVICES: Option<&RuntimeServices> = None;
+/// The normalized memory map, built by `uefi_start` and consumed by everything after it.
+static mut MEMORY_MAP: Option<MemoryMap> = None;
+
+const BOOT_STACK_SIZE: usize = 64 * 1024;
 
 
 #[entry]
@@ -54,21 +64,32 @@ fn uefi_start(image_handle: uefi::Handle, st: SystemTable<Boot>) -> Status {
     };
 
     kernlog::init();
-    unsafe { kernalloc::init(&BOOT_SERVICES).unwrap(); };
+    let config = BootConfig::load(st.boot_services(), image_handle);
+    kernlog::set_level(config.log_level);
+    unsafe { kernalloc::init(&BOOT_SERVICES, &config).unwrap(); };
     let rev = st.uefi_revision();
     info!("Welcome to NinOS on UEFI v{}.{}!", rev.major(), rev.minor());
 
     let bs = st.boot_services();
 
-    //let emergency_heap = bs.allocate_pool(MemoryType::LOADER_DATA, n_mib_bytes!(1) as usize).unwrap();
-    let mut memory_descriptors: Vec<MemoryDescriptor> = Vec::with_capacity(200);
-
-
     info!("1");
     let n: usize = 2 * bs.memory_map_size();
     let mmap_buf_ptr = bs.allocate_pool(MemoryType::LOADER_DATA, n).unwrap().unwrap();
     let mut mmap_buf = unsafe { slice::from_raw_parts_mut(mmap_buf_ptr, n) };
 
+    // The firmware's stack is of unknown size and lives in boot services memory, which we're going
+    // to reclaim. Switch to one of our own as soon as we're done with boot services.
+    let boot_stack = bs.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, BOOT_STACK_SIZE / 4096).unwrap().unwrap();
+
+    // The ACPI tables are needed later on to find the interrupt controllers.
+    let rsdp = st.config_table().iter()
+        .find(|it| it.guid == ACPI2_GUID)
+        .or_else(|| st.config_table().iter().find(|it| it.guid == ACPI_GUID))
+        .expect("Firmware provides no ACPI tables.");
+    arch::amd64::acpi::set_rsdp(x86_64::PhysAddr::new(rsdp.address as u64));
+
+    wallclock::init_from_uefi(st.runtime_services());
+
     info!("Exiting boot services.. i'm gonna be silent for some time now..");
     let res1 = match st.exit_boot_services(image_handle, &mut mmap_buf) {
         Ok(res) => res,
@@ -79,36 +100,36 @@ fn uefi_start(image_handle: uefi::Handle, st: SystemTable<Boot>) -> Status {
     // We're on our own now.
     // Memory allocator is now no longer available,  so we can't do anything fancy until we get an
     // allocator running.
-    //
-    // TODO what happens with the stack?
 
     let st = res1.0;
-    for desc in res1.1.map(|it| { let mut modified = it.clone(); modified.virt_start = modified.phys_start; modified }) {
-        memory_descriptors.push(desc);
+    unsafe {
+        MEMORY_MAP = Some(MemoryMap::new());
+        let memory_map = MEMORY_MAP.as_mut().unwrap();
+        memory_map.copy_from(res1.1, config.max_memory_descriptors);
+        memory_map.normalize();
     }
-    //memory_descriptors.sort_by_key(|it| it.phys_start); TODO this fails because it allocates
-    // memory, see core documentation
-    /*for (i, v) in memory_descriptors.enumerate() {
-        //unsafe { mmap_[i] = Some(v); }
-    }*/
-    //let present_memory_descriptors: Vec<MemoryDescriptor> = mmap_.iter().filter_map(|it| it.as_deref())
-
-    //let need_to_fill_in_virt_addrs = true;
-    /*if need_to_fill_in_virt_addrs {
-        for desc in present_memory_descriptors {
-            desc.virt_start = desc.phys_start;
-        }
-    }*/
 
-    //panic!("whoa!");
-    let mmap_iter = memory_descriptors.iter();
+    wallclock::init_from_rtc_if_needed();
 
     info!("Exiting kernalloc boot services..");
     unsafe { kernalloc::exit_boot_services(); }
 
-    arch::amd64::init(mmap_iter);
-    info!("We're still alive, hurray!");
+    unsafe {
+        arch::amd64::stack::switch_to(x86_64::VirtAddr::new(boot_stack + BOOT_STACK_SIZE as u64), continue_on_boot_stack);
+    }
+}
 
+/// Rest of `uefi_start`, running on the boot stack. It has no guard page, as we're still running
+/// on the firmware's page tables, but only lasts until `arch::amd64::init` moves on to a kernel
+/// stack.
+extern "C" fn continue_on_boot_stack() -> ! {
+    // Lives on in the higher half, see arch::amd64::init.
+    let memory_descriptors = unsafe { MEMORY_MAP.as_ref().unwrap().as_slice() };
+    arch::amd64::init(memory_descriptors, kernel_main);
+}
+
+fn kernel_main(_memory_descriptors: &'static [MemoryDescriptor]) -> ! {
+    info!("We're still alive, hurray!");
 
     //info!("Ayy!");
     loop {}
@@ -156,17 +177,14 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
         }
     }
 
+    #[cfg(feature = "leak-tracking")]
+    kernalloc::leak_report();
+
     // Give the user some time to read the message
     if let Some(bs) = unsafe { BOOT_SERVICES } {
         bs.stall(10_000_000);
     } else {
-        let mut dummy = 0u64;
-        // FIXME: May need different counter values in debug & release builds
-        for i in 0..300_000_000 {
-            unsafe {
-                core::ptr::write_volatile(&mut dummy, i);
-            }
-        }
+        arch::amd64::time::busy_wait(core::time::Duration::from_secs(10));
     }
 
     powerdown();
@@ -195,7 +213,10 @@ fn eh_personality() {}
  * 0x(0000)800000000000: kernel code (LOADER_CODE)
  *                       kernel .text
  *                       kernel .bss
- *                       kernel heap
+ *
+ * 0x(0000)810000000000: kernel heap
+ *
+ * 0x(0000)820000000000: kernel stacks, each with a guard page below it
  *
  * 0x(0000)840000000000: MMIO & MMIO_PORT_SPACE
  *                       ACPI
@@ -0,0 +1,147 @@
+//! The physical memory map as the rest of the kernel sees it.
+//!
+//! The map the firmware hands us on exiting boot services comes in no particular order, may
+//! describe a single region in many pieces and, on some firmware, has regions overlap. A
+//! `MemoryMap` is a copy of it that is sorted by address, has adjacent regions of the same kind
+//! merged and no overlaps left. It has a fixed capacity, as it is built right after exiting boot
+//! services, before there is any allocator to rely on.
+use uefi::table::boot::{MemoryType, MemoryDescriptor};
+
+use log::{debug, info, warn, error};
+
+use heapless::consts::U512 as HLU512;
+
+const PAGE_SIZE: u64 = 4096;
+
+pub struct MemoryMap {
+    /// Sorted by start address and free of overlaps once `normalize` has run.
+    descriptors: heapless::Vec<MemoryDescriptor, HLU512>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self { descriptors: heapless::Vec::new() }
+    }
+
+    /// Replace the map by a copy of the firmware's, keeping at most `limit` descriptors. We never
+    /// call SetVirtualAddressMap, so every region's virtual address is its physical one.
+    pub fn copy_from<'a, I>(&mut self, descriptors: I, limit: usize) where I: Iterator<Item = &'a MemoryDescriptor> {
+        let limit = core::cmp::min(limit, self.descriptors.capacity());
+        self.descriptors.clear();
+        for desc in descriptors {
+            if self.descriptors.len() == limit {
+                warn!("Memory map has more than {} descriptors, ignoring the rest.", limit);
+                break;
+            }
+            let mut desc = *desc;
+            desc.virt_start = desc.phys_start;
+            self.descriptors.push(desc).unwrap();
+        }
+    }
+
+    /// Sort the map, resolve overlapping regions and merge adjacent regions of the same type and
+    /// attributes.
+    pub fn normalize(&mut self) {
+        let before = self.descriptors.len();
+        let mut i = 0;
+        while i < self.descriptors.len() {
+            if self.descriptors[i].page_count == 0 { self.descriptors.swap_remove(i); } else { i += 1; }
+        }
+        self.descriptors.sort_unstable_by_key(|it| it.phys_start);
+        self.resolve_overlaps();
+        self.merge();
+        info!("Memory map normalized from {} to {} descriptors.", before, self.descriptors.len());
+    }
+
+    pub fn as_slice(&self) -> &[MemoryDescriptor] {
+        &self.descriptors
+    }
+
+    /// Cut regions down until none of them overlap, keeping the map sorted.
+    ///
+    /// Memory we'd hand out always gives way to memory that is in use by something else. When
+    /// neither or both are, the region starting later gives way, as there's no telling which of
+    /// the two the firmware meant.
+    fn resolve_overlaps(&mut self) {
+        // Sorted by start, so any overlap shows up between neighbours.
+        loop {
+            let overlap = (1..self.descriptors.len()).find(|i| end(&self.descriptors[i - 1]) > self.descriptors[*i].phys_start);
+            let i = match overlap {
+                Some(i) => i,
+                None => break,
+            };
+            let (first, second) = (self.descriptors[i - 1], self.descriptors[i]);
+            let (victim, other) = if is_available(first.ty) && !is_available(second.ty) { (i - 1, second) } else { (i, first) };
+
+            if is_available(self.descriptors[victim].ty) != is_available(other.ty) {
+                debug!("Truncating {:?} region at 0x{:X} against {:?} region at 0x{:X}.",
+                    self.descriptors[victim].ty, self.descriptors[victim].phys_start, other.ty, other.phys_start);
+            } else {
+                warn!("{:?} region at 0x{:X} overlaps {:?} region at 0x{:X}, truncating the former.",
+                    self.descriptors[victim].ty, self.descriptors[victim].phys_start, other.ty, other.phys_start);
+            }
+            self.subtract(victim, &other);
+            self.descriptors.sort_unstable_by_key(|it| it.phys_start);
+        }
+    }
+
+    /// Remove the part of the region at `index` that `other` covers, which may split it in two.
+    fn subtract(&mut self, index: usize, other: &MemoryDescriptor) {
+        let desc = self.descriptors[index];
+        let below = with_range(&desc, desc.phys_start, core::cmp::min(end(&desc), other.phys_start));
+        let above = with_range(&desc, core::cmp::max(desc.phys_start, end(other)), end(&desc));
+
+        match (below, above) {
+            (Some(below), Some(above)) => {
+                self.descriptors[index] = below;
+                if self.descriptors.push(above).is_err() {
+                    error!("Memory map is full, dropping the {:?} region at 0x{:X}.", above.ty, above.phys_start);
+                }
+            }
+            (Some(part), None) | (None, Some(part)) => self.descriptors[index] = part,
+            (None, None) => { self.descriptors.swap_remove(index); }
+        }
+    }
+
+    /// Merge neighbours of the same type and attributes that touch.
+    fn merge(&mut self) {
+        let mut merged = 0;
+        for i in 0..self.descriptors.len() {
+            let desc = self.descriptors[i];
+            if merged > 0 {
+                let last = &mut self.descriptors[merged - 1];
+                if last.ty == desc.ty && last.att == desc.att && end(last) == desc.phys_start {
+                    last.page_count += desc.page_count;
+                    continue;
+                }
+            }
+            self.descriptors[merged] = desc;
+            merged += 1;
+        }
+        self.descriptors.truncate(merged);
+    }
+}
+
+/// Whether memory of the given type is free for us to use, now or once boot services are gone.
+fn is_available(ty: MemoryType) -> bool {
+    match ty {
+        MemoryType::CONVENTIONAL => true,
+        MemoryType::BOOT_SERVICES_CODE => true,
+        MemoryType::BOOT_SERVICES_DATA => true,
+        _ => false,
+    }
+}
+
+fn end(desc: &MemoryDescriptor) -> u64 {
+    desc.phys_start + desc.page_count * PAGE_SIZE
+}
+
+/// A copy of `desc` covering `start..end` instead, if that is not empty.
+fn with_range(desc: &MemoryDescriptor, start: u64, end: u64) -> Option<MemoryDescriptor> {
+    if start >= end { return None; }
+    let mut desc = *desc;
+    desc.phys_start = start;
+    desc.virt_start = start;
+    desc.page_count = (end - start) / PAGE_SIZE;
+    Some(desc)
+}
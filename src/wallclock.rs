@@ -0,0 +1,150 @@
+//! Wall-clock time: the time of day read once during boot, from UEFI or else the CMOS RTC, moved
+//! forward by the monotonic clock.
+use core::fmt;
+
+use uefi::table::runtime::{RuntimeServices, Time};
+
+use log::{info, warn};
+
+use crate::arch::amd64::{rtc, time};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// A calendar date and time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch.
+    pub fn unix_timestamp(&self) -> i64 {
+        days_from_civil(self.year as i64, self.month as i64, self.day as i64) * SECS_PER_DAY
+            + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    fn from_unix_timestamp(secs: i64, nanosecond: u32) -> Self {
+        let (days, secs_of_day) = (secs.div_euclid(SECS_PER_DAY), secs.rem_euclid(SECS_PER_DAY));
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            nanosecond,
+        }
+    }
+}
+
+/// ISO 8601, to the millisecond.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.nanosecond / 1_000_000)
+    }
+}
+
+/// A point in time, as nanoseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UtcTime(pub i128);
+
+impl UtcTime {
+    pub fn unix_timestamp(&self) -> i64 {
+        self.0.div_euclid(NANOS_PER_SEC as i128) as i64
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_timestamp(self.unix_timestamp(), self.0.rem_euclid(NANOS_PER_SEC as i128) as u32)
+    }
+}
+
+impl fmt::Display for UtcTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.date_time().fmt(f)
+    }
+}
+
+/// The time read during boot and the TSC at that moment.
+static mut BOOT_TIME: Option<(UtcTime, u64)> = None;
+
+/// Read the time from the firmware. To be called before exiting boot services.
+pub fn init_from_uefi(rs: &RuntimeServices) {
+    match rs.get_time() {
+        Ok(completion) => set_boot_time(from_uefi_time(&completion.unwrap()), "UEFI"),
+        Err(err) => warn!("Failed to get the time from UEFI: {:?}", err.status()),
+    }
+}
+
+/// Read the time from the CMOS RTC, unless `init_from_uefi` already got it.
+pub fn init_from_rtc_if_needed() {
+    if is_available() { return; }
+    set_boot_time(utc_from_date_time(&rtc::read()), "the CMOS RTC");
+}
+
+fn set_boot_time(boot_time: UtcTime, source: &str) {
+    unsafe { BOOT_TIME = Some((boot_time, time::read_tsc())); }
+    info!("Time is {}, according to {}.", boot_time, source);
+}
+
+fn utc_from_date_time(date_time: &DateTime) -> UtcTime {
+    UtcTime(date_time.unix_timestamp() as i128 * NANOS_PER_SEC as i128 + date_time.nanosecond as i128)
+}
+
+/// UEFI keeps local time along with its offset from UTC in minutes, if it knows it. Without one
+/// we take it to be UTC.
+fn from_uefi_time(uefi_time: &Time) -> UtcTime {
+    let local = utc_from_date_time(&DateTime {
+        year: uefi_time.year(),
+        month: uefi_time.month(),
+        day: uefi_time.day(),
+        hour: uefi_time.hour(),
+        minute: uefi_time.minute(),
+        second: uefi_time.second(),
+        nanosecond: uefi_time.nanosecond(),
+    });
+    let offset = uefi_time.time_zone().unwrap_or(0) as i128 * 60 * NANOS_PER_SEC as i128;
+    UtcTime(local.0 - offset)
+}
+
+pub fn is_available() -> bool {
+    unsafe { BOOT_TIME.is_some() }
+}
+
+/// The current time, if it has been read yet. It stands still until the monotonic clock is
+/// calibrated.
+pub fn now() -> Option<UtcTime> {
+    unsafe { BOOT_TIME }.map(|(boot_time, tsc)| UtcTime(boot_time.0 + time::ns_since(tsc) as i128))
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}